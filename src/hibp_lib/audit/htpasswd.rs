@@ -0,0 +1,41 @@
+use super::{sha_scheme, Account};
+
+/// Parses `user:password` lines of an htpasswd file.
+/// Only `{SHA}` (unsalted SHA1) passwords can be checked, the other schemes are salted.
+pub fn parse_line(line: &str) -> anyhow::Result<Account> {
+    let (user, password) = line
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Expected user:password"))?;
+    let sha = password
+        .get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("{SHA}"));
+    let (hash, note) = if let Some(encoded) = password.get(5..).filter(|_| sha) {
+        (Some(sha_scheme(encoded)?), None)
+    } else {
+        (
+            None,
+            Some(format!("not checkable: {}", scheme_name(password))),
+        )
+    };
+    Ok(Account {
+        user: user.to_string(),
+        rid: None,
+        hash,
+        note,
+    })
+}
+
+fn scheme_name(password: &str) -> &'static str {
+    if password.starts_with("$apr1$") {
+        "salted MD5 ($apr1$)"
+    } else if ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|p| password.starts_with(p))
+    {
+        "bcrypt"
+    } else if password.starts_with("$5$") || password.starts_with("$6$") {
+        "salted SHA-2 crypt"
+    } else {
+        "crypt or plaintext"
+    }
+}
@@ -0,0 +1,113 @@
+use base64::Engine;
+use tracing::warn;
+
+use super::{
+    super::{check_password::hash_password, dataset::HashMode},
+    sha_scheme, Account,
+};
+
+/// Parses the `userPassword` attributes of every entry in an LDIF export.
+/// Entries without one are skipped, and an entry with several gets an account for each.
+/// Invalid entries are skipped with a warning.
+pub fn parse(ldif: &str) -> Vec<Account> {
+    let mut accounts = Vec::new();
+    for (number, entry) in entries(ldif).into_iter().enumerate() {
+        match parse_entry(&entry) {
+            Ok(entry_accounts) => accounts.extend(entry_accounts),
+            Err(e) => warn!("Skipping entry {}: {e}", number + 1),
+        }
+    }
+    accounts
+}
+
+fn parse_entry(entry: &[String]) -> anyhow::Result<Vec<Account>> {
+    let mut dn = None;
+    let mut passwords = Vec::new();
+    for line in entry {
+        let (name, value) = attribute(line)?;
+        if name.eq_ignore_ascii_case("dn") {
+            dn = Some(String::from_utf8_lossy(&value).into_owned());
+        } else if name.eq_ignore_ascii_case("userPassword") {
+            passwords.push(value);
+        }
+    }
+    let Some(dn) = dn else {
+        return Ok(Vec::new());
+    };
+    passwords
+        .iter()
+        .map(|password| {
+            let (hash, note) = password_hash(password)?;
+            Ok(Account {
+                user: dn.clone(),
+                rid: None,
+                hash,
+                note,
+            })
+        })
+        .collect()
+}
+
+/// Splits the LDIF into entries (separated by blank lines), unfolding continued lines
+/// (which start with a space) and dropping comments.
+fn entries(ldif: &str) -> Vec<Vec<String>> {
+    let mut entries = Vec::new();
+    let mut entry: Vec<String> = Vec::new();
+    for line in ldif.lines() {
+        if line.trim().is_empty() {
+            if !entry.is_empty() {
+                entries.push(std::mem::take(&mut entry));
+            }
+        } else if let Some(continued) = line.strip_prefix(' ') {
+            if let Some(last) = entry.last_mut() {
+                last.push_str(continued);
+            }
+        } else if !line.starts_with('#') {
+            entry.push(line.to_string());
+        }
+    }
+    if !entry.is_empty() {
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Parses `name: value` or `name:: base64 value`. Attribute options (`name;lang-en`) are dropped.
+fn attribute(line: &str) -> anyhow::Result<(&str, Vec<u8>)> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Invalid LDIF line {line:?}"))?;
+    let name = name.split(';').next().unwrap_or_default();
+    let value = match value.strip_prefix(':') {
+        Some(encoded) => base64::engine::general_purpose::STANDARD.decode(encoded.trim())?,
+        None => value.trim_start().as_bytes().to_vec(),
+    };
+    Ok((name, value))
+}
+
+/// Returns the SHA1 hash to check, or a note saying why it can't be checked.
+/// A `userPassword` without a `{SCHEME}` is stored in plaintext, so it is hashed and checked.
+fn password_hash(password: &[u8]) -> anyhow::Result<(Option<String>, Option<String>)> {
+    let password = String::from_utf8_lossy(password);
+    let Some(scheme_end) = password
+        .starts_with('{')
+        .then(|| password.find('}'))
+        .flatten()
+    else {
+        return Ok((
+            Some(hash_password(HashMode::Sha1, &password)),
+            Some("stored in plaintext".to_string()),
+        ));
+    };
+    let scheme = &password[..=scheme_end];
+    if scheme.eq_ignore_ascii_case("{SHA}") {
+        return Ok((Some(sha_scheme(&password[scheme_end + 1..])?), None));
+    }
+    let upper = scheme.to_ascii_uppercase();
+    let reason = match upper.as_str() {
+        "{SSHA}" | "{SSHA256}" | "{SSHA384}" | "{SSHA512}" | "{SMD5}" | "{CRYPT}" => "salted",
+        _ if upper.starts_with("{PBKDF2") || upper.starts_with("{ARGON2") => "salted",
+        _ => "not unsalted SHA1",
+    };
+    Ok((None, Some(format!("not checkable: {scheme} is {reason}"))))
+}
@@ -0,0 +1,223 @@
+mod htpasswd;
+mod ldif;
+mod pwdump;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read, Write},
+    path::Path,
+};
+
+use base64::Engine;
+use serde::Serialize;
+use tracing::warn;
+
+use super::{
+    config::{AuditInput, ReportFormat},
+    dataset::{encode_hex, Dataset, HashMode},
+    open_output,
+};
+
+/// One account (or LDIF entry) from the input.
+pub struct Account {
+    user: String,
+    /// Only pwdump lines have a RID.
+    rid: Option<String>,
+    /// `None` if the password is stored in a way that can't be checked.
+    hash: Option<String>,
+    /// Anything the report should point out about how the password is stored.
+    note: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    accounts_checked: usize,
+    compromised_accounts: usize,
+    shared_password_groups: usize,
+    not_checkable_accounts: usize,
+    /// Only accounts that are compromised, share a password or have a note are listed.
+    accounts: Vec<AccountReport>,
+}
+
+#[derive(Serialize)]
+struct AccountReport {
+    user: String,
+    rid: Option<String>,
+    /// `None` when redacted or not checkable.
+    hash: Option<String>,
+    /// How many times the password was seen in breaches (0 if never).
+    /// `None` if it couldn't be checked.
+    breach_count: Option<u32>,
+    /// Accounts with the same password have the same group number.
+    shared_group: Option<usize>,
+    shared_group_size: usize,
+    note: Option<String>,
+}
+
+impl AuditInput {
+    /// The kind of dataset the input's hashes are checked against.
+    fn mode(self) -> HashMode {
+        match self {
+            AuditInput::Pwdump => HashMode::Ntlm,
+            AuditInput::Ldif | AuditInput::Htpasswd => HashMode::Sha1,
+        }
+    }
+}
+
+/// Decodes the base64 SHA1 digest of a `{SHA}` password into hex.
+fn sha_scheme(encoded: &str) -> anyhow::Result<String> {
+    let digest = base64::engine::general_purpose::STANDARD.decode(encoded.trim())?;
+    if digest.len() != 20 {
+        anyhow::bail!("{{SHA}} password is {} bytes, not 20", digest.len());
+    }
+    Ok(encode_hex(&digest))
+}
+
+fn read_accounts(
+    reader: Box<dyn BufRead>,
+    input_format: AuditInput,
+) -> anyhow::Result<Vec<Account>> {
+    let parse_line = match input_format {
+        AuditInput::Pwdump => pwdump::parse_line,
+        AuditInput::Htpasswd => htpasswd::parse_line,
+        AuditInput::Ldif => {
+            let mut ldif = String::new();
+            { reader }.read_to_string(&mut ldif)?;
+            return Ok(ldif::parse(&ldif));
+        }
+    };
+    let mut accounts = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line.trim()) {
+            Ok(account) => accounts.push(account),
+            Err(e) => warn!("Skipping line {}: {e}", number + 1),
+        }
+    }
+    Ok(accounts)
+}
+
+/// Audits the password hashes of a pwdump/secretsdump extract (against an NTLM dataset),
+/// or of an LDIF export or htpasswd file (against a SHA1 dataset).
+///
+/// Accounts are reported if their password was seen in a breach, if they share
+/// their password with another account in the input, or if their password is stored
+/// in a way that can't be checked (ie. salted) or shouldn't be (plaintext).
+pub fn run_audit(
+    input: &Path,
+    input_format: AuditInput,
+    dataset: &Path,
+    output: Option<&Path>,
+    format: ReportFormat,
+    redact: bool,
+) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    let mode = input_format.mode();
+    if dataset.mode() != mode {
+        anyhow::bail!(
+            "This input needs a {} dataset, but this is a {} dataset.",
+            mode.name(),
+            dataset.mode().name()
+        );
+    }
+    let reader: Box<dyn BufRead> = if input == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::new(std::fs::File::open(input)?))
+    };
+    let accounts = read_accounts(reader, input_format)?;
+
+    // Each distinct hash is only looked up once
+    let mut counts = HashMap::new();
+    let mut accounts_per_hash = HashMap::<&str, usize>::new();
+    for hash in accounts.iter().filter_map(|a| a.hash.as_deref()) {
+        if !counts.contains_key(hash) {
+            counts.insert(hash, dataset.count(hash)?.unwrap_or(0));
+        }
+        *accounts_per_hash.entry(hash).or_default() += 1;
+    }
+    // Groups are numbered in the order they first appear
+    let mut groups = HashMap::new();
+    for hash in accounts.iter().filter_map(|a| a.hash.as_deref()) {
+        if accounts_per_hash[hash] > 1 {
+            let next = groups.len() + 1;
+            groups.entry(hash).or_insert(next);
+        }
+    }
+
+    let listed = accounts
+        .iter()
+        .map(|account| {
+            let hash = account.hash.as_deref();
+            AccountReport {
+                user: account.user.clone(),
+                rid: account.rid.clone(),
+                hash: hash.filter(|_| !redact).map(str::to_string),
+                breach_count: hash.map(|h| counts[h]),
+                shared_group: hash.and_then(|h| groups.get(h).copied()),
+                shared_group_size: hash.map(|h| accounts_per_hash[h]).unwrap_or(1),
+                note: account.note.clone(),
+            }
+        })
+        .filter(|report| {
+            report.breach_count.unwrap_or(0) > 0
+                || report.shared_group.is_some()
+                || report.note.is_some()
+        })
+        .collect::<Vec<_>>();
+    let report = Report {
+        accounts_checked: accounts.len(),
+        compromised_accounts: listed
+            .iter()
+            .filter(|r| r.breach_count.unwrap_or(0) > 0)
+            .count(),
+        shared_password_groups: groups.len(),
+        not_checkable_accounts: accounts.iter().filter(|a| a.hash.is_none()).count(),
+        accounts: listed,
+    };
+
+    let mut writer = open_output(output)?;
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &report)?;
+            writeln!(writer)?;
+        }
+        ReportFormat::Csv => write_csv(&mut writer, &report)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_csv(writer: &mut dyn Write, report: &Report) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "user,rid,hash,breach_count,shared_group,shared_group_size,note"
+    )?;
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    for account in &report.accounts {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{}",
+            csv_field(&account.user),
+            csv_field(account.rid.as_deref().unwrap_or_default()),
+            account.hash.as_deref().unwrap_or_default(),
+            optional(account.breach_count.map(|c| c.to_string())),
+            optional(account.shared_group.map(|g| g.to_string())),
+            account.shared_group_size,
+            csv_field(account.note.as_deref().unwrap_or_default()),
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes a field if it holds anything that would break the row.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
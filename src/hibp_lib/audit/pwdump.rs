@@ -0,0 +1,25 @@
+use super::{
+    super::dataset::{parse_hash, HashMode},
+    Account,
+};
+
+/// Parses `user:rid:lmhash:nthash:::` (pwdump and secretsdump output).
+/// Anything after the NT hash is ignored.
+pub fn parse_line(line: &str) -> anyhow::Result<Account> {
+    let mut fields = line.split(':');
+    let (Some(user), Some(rid), Some(_lm), Some(nt)) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        anyhow::bail!("Expected user:rid:lmhash:nthash:::");
+    };
+    let (mode, nt_hash) = parse_hash(nt)?;
+    if mode != HashMode::Ntlm {
+        anyhow::bail!("{nt:?} is not an NT hash");
+    }
+    Ok(Account {
+        user: user.to_string(),
+        rid: Some(rid.to_string()),
+        hash: Some(nt_hash),
+        note: None,
+    })
+}
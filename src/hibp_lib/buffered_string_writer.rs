@@ -1,20 +1,63 @@
+use anyhow::Context;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
 use std::{
     collections::VecDeque,
     path::{Path, PathBuf},
-    sync::atomic,
+    sync::{atomic, Arc},
 };
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use super::{stats::WRITTEN_TO_FILE, ChannelData};
+use super::{
+    config::OutputFormat,
+    consts::{BEGIN, LENGTH},
+    dataset::{
+        index::{index_path, PrefixIndex},
+        packed::{full_key_len, PackedWriter, DATA_OFFSET},
+        split_row, HashMode,
+    },
+    stats::Stats,
+    summary::format_checksum,
+    ChannelData,
+};
+
+type FileWriter = tokio::io::BufWriter<tokio::fs::File>;
+
+/// Where the downloaded prefixes end up.
+enum Sink {
+    /// One text file with the full hash on every row.
+    Text(FileWriter),
+    /// One file per prefix, as downloaded.
+    Directory,
+    /// One file in the packed binary format.
+    Packed(PackedWriter),
+}
 
 pub struct BufferedStringWriter {
     files: VecDeque<ChannelData>,
-    writer: Option<tokio::io::BufWriter<tokio::fs::File>>,
+    sink: Sink,
     path: PathBuf,
+    stats: Arc<Stats>,
+    /// Rows seen fewer times than this are left out.
+    min_count: u32,
+    /// Hashes the output in prefix order for the summary checksum.
+    hasher: Sha256,
+    /// The prefix that has to be written next to keep the output in order.
+    next_key: u32,
+    /// Bytes written to the single file so far.
+    position: u64,
+    /// Where each prefix starts in the single file, for the prefix offset index.
+    offsets: Vec<Option<u64>>,
 }
 
 impl BufferedStringWriter {
-    pub async fn from_file(filename: &Path) -> Result<Self, std::io::Error> {
+    pub async fn from_file(
+        filename: &Path,
+        format: OutputFormat,
+        mode: HashMode,
+        min_count: u32,
+        stats: Arc<Stats>,
+    ) -> Result<Self, std::io::Error> {
         let exists = tokio::fs::try_exists(filename).await?;
         let is_dir = if exists {
             let metadata = tokio::fs::metadata(filename).await?;
@@ -22,7 +65,13 @@ impl BufferedStringWriter {
         } else {
             false
         };
-        let writer = if exists && is_dir {
+        let sink = if exists && is_dir {
+            if format == OutputFormat::Packed {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "The packed format is a single file and can't be written to a directory.",
+                ));
+            }
             let mut dir_contents = tokio::fs::read_dir(filename).await?;
             if dir_contents.next_entry().await?.is_some() {
                 return Err(std::io::Error::new(
@@ -30,22 +79,37 @@ impl BufferedStringWriter {
                     "Directory downloads must be done with an empty directory.",
                 ));
             }
-            None
+            Sink::Directory
         } else {
-            Some(tokio::io::BufWriter::with_capacity(
-                1024 * 1024 * 32, // 1 download is around 32kB. This fits around 1024 downloads.
-                tokio::fs::File::create(filename).await?,
-            ))
+            match format {
+                OutputFormat::Text => Sink::Text(tokio::io::BufWriter::with_capacity(
+                    1024 * 1024 * 32, // 1 download is around 32kB. This fits around 1024 downloads.
+                    tokio::fs::File::create(filename).await?,
+                )),
+                OutputFormat::Packed => Sink::Packed(
+                    tokio::task::block_in_place(|| {
+                        PackedWriter::create(filename, mode, full_key_len(mode))
+                    })
+                    .map_err(into_io_error)?,
+                ),
+            }
         };
         Ok(Self {
             files: VecDeque::with_capacity(1024),
-            writer,
+            sink,
             path: filename.to_path_buf(),
+            stats,
+            min_count,
+            hasher: Sha256::new(),
+            next_key: BEGIN,
+            position: 0,
+            offsets: vec![None; LENGTH as usize],
         })
     }
 
     pub async fn add_file(&mut self, (n, content): ChannelData) -> Result<(), std::io::Error> {
         self.files.push_back((n, content));
+        self.update_buffered();
         if self.files.len() < 1024 {
             return Ok(());
         }
@@ -55,47 +119,154 @@ impl BufferedStringWriter {
         Ok(())
     }
 
-    #[allow(clippy::get_first)]
     pub async fn flush(&mut self, only_contiguous: bool) -> Result<(), std::io::Error> {
         // Sort by the 5 character key at the beginning of the file
         // This matches the first 5 characters of the first row
         self.files.make_contiguous().sort_unstable_by_key(|v| v.0);
-        let Some((mut current_key, _)) = self.files.get(0) else {
-            return Ok(());
-        };
-        // Peek at the key to check it is contiguous
-        while let Some(&(key, _)) = self.files.get(0) {
-            // Figure out when to break
-            if current_key != key {
-                if key == current_key + 1 || !only_contiguous {
-                    current_key = key;
-                } else {
-                    break;
-                }
+        // Peek at the key to check it continues from the last written key.
+        // (Failed prefixes are still sent, with no content, so they can't block this)
+        while let Some(&(key, _)) = self.files.front() {
+            if only_contiguous && key != self.next_key {
+                break;
             }
             let (n, text) = self.files.pop_front().unwrap();
-            if let Some(writer) = self.writer.as_mut() {
-                for line in String::from_utf8_lossy(&text).lines() {
-                    writer
-                        .write_all(format!("{n:05X}{line}\n").as_bytes())
-                        .await?;
+            self.next_key = n + 1;
+            if self.stats.is_failed_prefix(n) {
+                continue;
+            }
+            let mut bytes_written = 0;
+            let mut rows_dropped = 0;
+            let min_count = self.min_count;
+            match &mut self.sink {
+                Sink::Text(writer) => {
+                    self.offsets[n as usize] = Some(self.position);
+                    for line in String::from_utf8_lossy(&text).lines() {
+                        if !keep_row(line, min_count) {
+                            rows_dropped += 1;
+                            continue;
+                        }
+                        let row = format!("{n:05X}{line}\n");
+                        writer.write_all(row.as_bytes()).await?;
+                        self.hasher.update(row.as_bytes());
+                        bytes_written += row.len();
+                    }
+                }
+                Sink::Directory => {
+                    let filepath = self.path.join(format!("{n:05X}"));
+                    let mut file = tokio::fs::File::create(&filepath).await?;
+                    // Without a minimum the prefix is saved exactly as downloaded
+                    let text = if min_count > 1 {
+                        let text = String::from_utf8_lossy(&text);
+                        let kept = text
+                            .lines()
+                            .filter(|line| keep_row(line, min_count))
+                            .collect::<Vec<_>>();
+                        rows_dropped = text.lines().count() - kept.len();
+                        Bytes::from(kept.join("\r\n"))
+                    } else {
+                        text
+                    };
+                    file.write_all(&text).await?;
+                    self.hasher.update(&text);
+                    bytes_written += text.len();
                 }
-            } else {
-                let filepath = self.path.join(format!("{n:05X}"));
-                let mut file = tokio::fs::File::create(&filepath).await?;
-                file.write_all(&text).await?;
+                // The checksum is taken once the header is in place
+                Sink::Packed(writer) => tokio::task::block_in_place(|| {
+                    for line in String::from_utf8_lossy(&text).lines() {
+                        let (suffix, count) = split_row(line)
+                            .with_context(|| format!("Invalid row in prefix {n:05X}: {line:?}"))?;
+                        if count < min_count {
+                            rows_dropped += 1;
+                            continue;
+                        }
+                        bytes_written += writer.write_row(&format!("{n:05X}{suffix}"), count)?;
+                    }
+                    anyhow::Ok(())
+                })
+                .map_err(into_io_error)?,
             }
-            WRITTEN_TO_FILE.fetch_add(1, atomic::Ordering::AcqRel);
+            self.position += bytes_written as u64;
+            self.stats
+                .bytes_written
+                .fetch_add(bytes_written as u64, atomic::Ordering::AcqRel);
+            self.stats
+                .written_to_file
+                .fetch_add(1, atomic::Ordering::AcqRel);
+            self.stats.rows_written.fetch_add(
+                u64::from(self.stats.prefix_rows(n)) - rows_dropped as u64,
+                atomic::Ordering::AcqRel,
+            );
+            self.stats
+                .rows_dropped
+                .fetch_add(rows_dropped as u64, atomic::Ordering::AcqRel);
         }
+        self.update_buffered();
 
         Ok(())
     }
 
+    fn update_buffered(&self) {
+        self.stats
+            .buffered
+            .store(self.files.len() as u64, atomic::Ordering::Release);
+    }
+
     pub async fn inner_flush(&mut self) -> Result<(), std::io::Error> {
-        if let Some(w) = self.writer.as_mut() {
-            w.flush().await
-        } else {
-            Ok(())
+        match &mut self.sink {
+            Sink::Text(w) => w.flush().await,
+            // Flushed when it's finished
+            Sink::Directory | Sink::Packed(_) => Ok(()),
+        }
+    }
+
+    /// Flushes everything, writes the prefix offset index for a single file
+    /// (or the header and index for a packed file), and records the checksum of the output.
+    pub async fn finish(mut self) -> Result<(), std::io::Error> {
+        self.flush(false).await?;
+        self.inner_flush().await?;
+        let hasher = match self.sink {
+            Sink::Text(_) => {
+                let index = PrefixIndex::from_offsets(&self.offsets, self.position);
+                tokio::fs::write(index_path(&self.path), index.to_bytes()).await?;
+                self.hasher
+            }
+            Sink::Directory => self.hasher,
+            Sink::Packed(writer) => {
+                tokio::task::block_in_place(|| writer.finish()).map_err(into_io_error)?;
+                self.stats
+                    .bytes_written
+                    .fetch_add(DATA_OFFSET as u64, atomic::Ordering::AcqRel);
+                // The header is written last, so the file has to be read back to hash it
+                hash_file(&self.path).await?
+            }
+        };
+        self.stats.output_checksum.set(format_checksum(hasher)).ok();
+        Ok(())
+    }
+}
+
+/// Rows that can't be parsed are kept, so they aren't counted as dropped.
+fn keep_row(line: &str, min_count: u32) -> bool {
+    min_count <= 1 || split_row(line).is_none_or(|(_, count)| count >= min_count)
+}
+
+/// Keeps the kind of an underlying I/O error, otherwise the data was invalid.
+fn into_io_error(e: anyhow::Error) -> std::io::Error {
+    let kind = e
+        .downcast_ref::<std::io::Error>()
+        .map_or(std::io::ErrorKind::InvalidData, |e| e.kind());
+    std::io::Error::new(kind, format!("{e:#}"))
+}
+
+async fn hash_file(path: &Path) -> Result<Sha256, std::io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(hasher);
         }
+        hasher.update(&buf[..read]);
     }
 }
@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    dataset::index::{index_path, PrefixIndex},
+    progress_style::{get_span, leak_span, progress_style_sort},
+};
+
+/// Writes the prefix offset index (`.idx` sidecar) for an existing single file dataset.
+/// The downloader already writes one, so this is only needed for older datasets.
+pub fn run_index(dataset: &Path) -> anyhow::Result<()> {
+    if std::fs::metadata(dataset)?.is_dir() {
+        anyhow::bail!("Directory datasets don't need an index");
+    }
+    let span = get_span(std::fs::metadata(dataset)?.len(), progress_style_sort()).entered();
+
+    let index = PrefixIndex::build(dataset, |bytes| span.pb_inc(bytes))?;
+    std::fs::write(index_path(dataset), index.to_bytes())?;
+
+    leak_span(span);
+
+    Ok(())
+}
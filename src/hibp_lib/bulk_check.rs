@@ -0,0 +1,95 @@
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use extsort::ExternalSorter;
+use tracing::warn;
+
+use super::{
+    dataset::{parse_hash, Dataset},
+    open_output,
+    sort::row::MyStruct,
+};
+
+/// Checks a large list of hashes in one sequential pass over the dataset.
+///
+/// The input is sorted by hash with the same external sorter as the sort subcommand,
+/// then merge-joined against the dataset (which is already in hash order).
+/// Every input hash found in the dataset is written once as `HASH:COUNT`.
+pub fn run_bulk_check(
+    input: &Path,
+    dataset: &Path,
+    output: Option<&Path>,
+    temp_dir: &Path,
+) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    dataset.ensure_not_truncated()?;
+    let mode = dataset.mode();
+    std::fs::create_dir_all(temp_dir)?;
+
+    let reader: Box<dyn BufRead> = if input == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::with_capacity(
+            16 * 1024 * 1024,
+            std::fs::File::open(input)?,
+        ))
+    };
+    let hashes = reader.lines().filter_map(|line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to read input: {e}");
+                return None;
+            }
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        match parse_hash(&line) {
+            Ok((hash_mode, hash)) if hash_mode == mode => Some(MyStruct { count: 0, hash }),
+            Ok(_) => {
+                warn!("Skipping {} hash in a {} dataset", line.trim(), mode.name());
+                None
+            }
+            Err(e) => {
+                warn!("Skipping line: {e}");
+                None
+            }
+        }
+    });
+    // Same ~500 MB chunks as the sort subcommand
+    let sorted = ExternalSorter::new()
+        .with_parallel_sort()
+        .with_sort_dir(temp_dir.to_path_buf())
+        .with_segment_size(11_640_000)
+        .sort_by(hashes, |a, b| a.hash.cmp(&b.hash))?;
+
+    let mut writer = open_output(output)?;
+    let mut rows = dataset.rows()?;
+    let mut row = rows.next().transpose()?;
+    let mut last_written: Option<String> = None;
+    for wanted in sorted {
+        // Advance the dataset until it catches up to the wanted hash
+        while let Some(current) = &row {
+            if current.hash.as_str() >= wanted.hash.as_str() {
+                break;
+            }
+            row = rows.next().transpose()?;
+        }
+        let Some(current) = &row else {
+            break;
+        };
+        if current.hash == wanted.hash && last_written.as_deref() != Some(wanted.hash.as_str()) {
+            writeln!(writer, "{}:{}", current.hash, current.count)?;
+            last_written = Some(wanted.hash);
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+
+    // Remove the temp dir after the writing is finished.
+    std::fs::remove_dir_all(temp_dir)?;
+    Ok(())
+}
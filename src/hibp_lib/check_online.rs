@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use rand::{seq::SliceRandom, Rng};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::warn;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    build_client,
+    consts::{BEGIN, END, LENGTH},
+    dataset::{parse_hash, split_row, HashMode},
+    download::download_prefix,
+    open_output,
+    progress_style::{get_span, leak_span, progress_style_sort},
+    stats::Stats,
+};
+
+/// The suffixes of one range response, with their counts.
+type Range = HashMap<String, u32>;
+
+/// Range responses cached on disk as `${DIR}/sha1/${PREFIX}` and `${DIR}/ntlm/${PREFIX}`.
+/// A response is reused until it is older than the TTL.
+#[derive(Clone)]
+struct RangeCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl RangeCache {
+    fn path(&self, mode: HashMode, n: u32) -> PathBuf {
+        let mode = match mode {
+            HashMode::Sha1 => "sha1",
+            HashMode::Ntlm => "ntlm",
+        };
+        self.dir.join(mode).join(format!("{n:05X}"))
+    }
+
+    /// Returns `None` if the prefix isn't cached or has expired.
+    async fn get(&self, mode: HashMode, n: u32) -> Option<Bytes> {
+        let path = self.path(mode, n);
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        // A modified time in the future counts as fresh
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age >= self.ttl {
+            return None;
+        }
+        tokio::fs::read(&path).await.ok().map(Bytes::from)
+    }
+
+    /// Writes to a temporary file first so a concurrent run never reads a partial file.
+    async fn put(&self, mode: HashMode, n: u32, body: &[u8]) -> std::io::Result<()> {
+        let path = self.path(mode, n);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, body).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+}
+
+/// Checks hashes against the online range API without downloading the whole corpus.
+///
+/// Each prefix needed by the input is fetched once (or read from the cache), and the
+/// suffixes are matched locally. Every input hash is written as `HASH:COUNT`,
+/// in input order, with a count of 0 if it was never seen.
+///
+/// With `decoys`, that many random prefixes are requested alongside each real one.
+pub fn run_check_online(
+    input: &Path,
+    output: Option<&Path>,
+    cache_dir: &Path,
+    cache_ttl: Duration,
+    concurrent_requests: usize,
+    decoys: usize,
+    add_padding: bool,
+) -> anyhow::Result<()> {
+    let reader: Box<dyn BufRead> = if input == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::new(std::fs::File::open(input)?))
+    };
+    let mut hashes = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_hash(&line) {
+            Ok(hash) => hashes.push(hash),
+            Err(e) => warn!("Skipping line: {e}"),
+        }
+    }
+    let prefixes = hashes
+        .iter()
+        .map(|(mode, hash)| (*mode, prefix_of(hash)))
+        .collect::<BTreeSet<_>>();
+    let prefixes = with_decoys(&prefixes, decoys);
+
+    let span = get_span(prefixes.len() as u64, progress_style_sort()).entered();
+    let cache = RangeCache {
+        dir: cache_dir.to_path_buf(),
+        ttl: cache_ttl,
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let ranges = runtime.block_on(fetch_ranges(
+        prefixes,
+        cache,
+        concurrent_requests,
+        add_padding,
+        &span,
+    ))?;
+
+    let mut writer = open_output(output)?;
+    for (mode, hash) in &hashes {
+        let count = ranges[&(*mode, prefix_of(hash))]
+            .get(&hash[5..])
+            .copied()
+            .unwrap_or(0);
+        writeln!(writer, "{hash}:{count}")?;
+    }
+    writer.flush()?;
+
+    leak_span(span);
+
+    Ok(())
+}
+
+/// Adds `decoys` random prefixes (of the same mode) for every real prefix, and shuffles
+/// them together. Decoys are fetched, cached and parsed exactly like the real prefixes
+/// so that nothing in the traffic tells them apart, and their results are ignored.
+fn with_decoys(prefixes: &BTreeSet<(HashMode, u32)>, decoys: usize) -> Vec<(HashMode, u32)> {
+    let mut rng = rand::thread_rng();
+    let mut all = prefixes.clone();
+    let mut per_mode = HashMap::<HashMode, usize>::new();
+    for &(mode, _) in prefixes {
+        *per_mode.entry(mode).or_default() += 1;
+    }
+    for &(mode, _) in prefixes {
+        let used = per_mode.get_mut(&mode).unwrap();
+        // Stop early if every prefix of this mode is already being requested
+        for _ in 0..decoys {
+            if *used == LENGTH as usize {
+                break;
+            }
+            while !all.insert((mode, rng.gen_range(BEGIN..=END))) {}
+            *used += 1;
+        }
+    }
+    let mut all = all.into_iter().collect::<Vec<_>>();
+    all.shuffle(&mut rng);
+    all
+}
+
+async fn fetch_ranges(
+    prefixes: Vec<(HashMode, u32)>,
+    cache: RangeCache,
+    concurrent_requests: usize,
+    add_padding: bool,
+    span: &tracing::Span,
+) -> anyhow::Result<HashMap<(HashMode, u32), Range>> {
+    let client = build_client();
+    let stats = Arc::new(Stats::new());
+    let semaphore = Arc::new(Semaphore::new(concurrent_requests));
+    let mut handles = JoinSet::new();
+    for (mode, n) in prefixes {
+        let client = client.clone();
+        let cache = cache.clone();
+        let stats = Arc::clone(&stats);
+        let semaphore = Arc::clone(&semaphore);
+        handles.spawn(async move {
+            let _permit = semaphore.acquire().await?;
+            let body = match cache.get(mode, n).await {
+                Some(body) => body,
+                None => {
+                    let ntlm = mode == HashMode::Ntlm;
+                    let (_, body) = download_prefix(&client, n, ntlm, add_padding, None, &stats)
+                        .await
+                        .with_context(|| format!("Failed to fetch prefix {n:05X}"))?;
+                    if let Err(e) = cache.put(mode, n, &body).await {
+                        warn!("Failed to cache prefix {n:05X}: {e}");
+                    }
+                    body
+                }
+            };
+            let range = String::from_utf8_lossy(&body)
+                .lines()
+                .filter_map(split_row)
+                .map(|(suffix, count)| (suffix.to_ascii_uppercase(), count))
+                .collect::<Range>();
+            anyhow::Ok(((mode, n), range))
+        });
+    }
+
+    let mut ranges = HashMap::new();
+    while let Some(res) = handles.join_next().await {
+        let (key, range) = res??;
+        ranges.insert(key, range);
+        span.pb_inc(1);
+    }
+    Ok(ranges)
+}
+
+fn prefix_of(hash: &str) -> u32 {
+    u32::from_str_radix(&hash[..5], 16).expect("hashes are validated hex")
+}
@@ -0,0 +1,48 @@
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use md4::Md4;
+use sha1::{Digest, Sha1};
+
+use super::dataset::{encode_hex, Dataset, HashMode};
+
+/// Hashes a plaintext password the same way the dataset's hashes were made.
+/// NTLM is MD4 of the UTF-16LE encoded password.
+pub fn hash_password(mode: HashMode, password: &str) -> String {
+    let digest = match mode {
+        HashMode::Sha1 => Sha1::digest(password.as_bytes()).to_vec(),
+        HashMode::Ntlm => {
+            let utf16 = password
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>();
+            Md4::digest(utf16).to_vec()
+        }
+    };
+    encode_hex(&digest)
+}
+
+/// Prints how many times each password was seen (0 if never).
+/// The passwords themselves are never printed or logged.
+/// Prompts without echo on a TTY, otherwise (or with `batch`) reads one password per line from stdin.
+pub fn run_check_password(dataset: &Path, batch: bool) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    let count = |password: &str| -> anyhow::Result<u32> {
+        let hash = hash_password(dataset.mode(), password);
+        Ok(dataset.count(&hash)?.unwrap_or(0))
+    };
+
+    if batch || !std::io::stdin().is_terminal() {
+        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        for password in std::io::stdin().lock().lines() {
+            writeln!(stdout, "{}", count(&password?)?)?;
+        }
+        stdout.flush()?;
+    } else {
+        let password = rpassword::prompt_password("Password: ")?;
+        println!("{}", count(&password)?);
+    }
+    Ok(())
+}
@@ -1,6 +1,6 @@
-use std::{path::PathBuf, sync::OnceLock};
+use std::{net::SocketAddr, path::PathBuf, sync::OnceLock};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about)]
@@ -17,6 +17,25 @@ pub struct Config {
     /// Download NTLM hashes instead of SHA1 hashes
     #[arg(short, long)]
     pub ntlm: bool,
+    /// Send the Add-Padding header, so each response is padded with fake
+    /// ${SUFFIX}:0 rows and its size doesn't reveal the prefix.
+    /// The padding is removed before anything is written.
+    #[arg(long, verbatim_doc_comment)]
+    pub add_padding: bool,
+    /// The manifest of an earlier download of the same hashes. Prefixes that
+    /// have far fewer rows than they had then (see --anomaly-threshold) are
+    /// retried, and if they still do, kept but flagged in the summary and the
+    /// manifest, and the exit code is 4.
+    #[arg(long, verbatim_doc_comment)]
+    pub previous_manifest: Option<PathBuf>,
+    /// The fraction of a prefix's previous rows it can lose before it's anomalous.
+    /// A prefix dropping to no rows always is.
+    #[arg(long, default_value_t = 0.5, value_parser = parse_fraction, verbatim_doc_comment)]
+    pub anomaly_threshold: f64,
+    /// Only write hashes seen at least this many times.
+    /// The dropped rows are counted in the summary and the manifest.
+    #[arg(long, default_value_t = 1, verbatim_doc_comment)]
+    pub min_count: u32,
     /// The file or folder where the output will be written.
     /// Defaults to a single file that writes all hashes to one file.
     /// If an existing directory is chosen, it will save the downloaded data
@@ -29,6 +48,79 @@ pub struct Config {
         verbatim_doc_comment
     )]
     pub output_path: PathBuf,
+    /// The format of a single file download.
+    /// text: ${HASH}:${COUNT} rows, the same as the API returns them.
+    /// packed: The compact binary format (see the pack subcommand).
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        verbatim_doc_comment
+    )]
+    pub format: OutputFormat,
+    /// How progress is reported.
+    /// bar: An interactive progress bar on stderr.
+    /// json: One line of JSON stats on stdout every --progress-interval seconds.
+    /// none: Only warnings, errors and the final summary are printed.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProgressMode::Bar,
+        global = true,
+        verbatim_doc_comment
+    )]
+    pub progress: ProgressMode,
+    /// The number of seconds between each line of JSON progress.
+    #[arg(
+        long,
+        default_value_t = 10,
+        global = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub progress_interval: u64,
+    /// Serve Prometheus metrics over HTTP at http://${THISVAR}/metrics
+    /// for as long as the command runs. (ie. 127.0.0.1:9184)
+    #[arg(long, global = true, verbatim_doc_comment)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Write Prometheus metrics to this file every 5 seconds and when the
+    /// command finishes. (For node_exporter's textfile collector)
+    #[arg(long, global = true, verbatim_doc_comment)]
+    pub metrics_textfile: Option<PathBuf>,
+    /// Write the JSON summary of the run to this file instead of printing it to stdout.
+    #[arg(long, global = true)]
+    pub summary_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    Bar,
+    Json,
+    None,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Packed,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditInput {
+    Pwdump,
+    Ldif,
+    Htpasswd,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -64,7 +156,300 @@ pub enum Commands {
             verbatim_doc_comment
         )]
         temp_dir: PathBuf,
+        /// Only write hashes seen at least this many times.
+        #[arg(long, default_value_t = 1)]
+        min_count: u32,
+    },
+    /// Write the N most frequently seen hashes in descending frequency order.
+    /// Unlike sort, this reads the dataset once without temporary files,
+    /// and only holds N rows in memory.
+    #[command(name = "top", verbatim_doc_comment)]
+    Top {
+        /// The dataset to read, in any format.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// Write the rows to this file instead of stdout.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// How many hashes to write.
+        #[arg(long, default_value_t = 1_000_000)]
+        n: usize,
+    },
+    /// Describe the counts in a dataset: total rows and occurrences, a histogram
+    /// of counts, rows per prefix, and what fraction of all occurrences the
+    /// top 1, 10, 100... hashes cover.
+    #[command(name = "stats", verbatim_doc_comment)]
+    Stats {
+        /// The dataset to read, in any format.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// Write the report to this file instead of stdout.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// The format of the report.
+        #[arg(long, value_enum, default_value_t = StatsFormat::Text)]
+        report_format: StatsFormat,
+    },
+    /// Look up how many times a hash appears in a downloaded dataset.
+    #[command(name = "lookup")]
+    Lookup {
+        /// The SHA1 or NTLM hash (in hex) to look up.
+        /// If omitted or "-", hashes are read from stdin (one per line)
+        /// and each is printed as ${HASH}:${COUNT} or ${HASH}:not found.
+        #[arg(verbatim_doc_comment)]
+        hash: Option<String>,
+        /// The dataset to search. Either a single file or a directory
+        /// written by the downloader.
+        #[arg(
+            long,
+            default_value = "./hibp_password_hashes.txt",
+            verbatim_doc_comment
+        )]
+        dataset: PathBuf,
+    },
+    /// Check plaintext passwords against a downloaded dataset.
+    /// Only the count is printed (0 if never seen), never the password.
+    #[command(name = "check-password", verbatim_doc_comment)]
+    CheckPassword {
+        /// The dataset to search. SHA1 datasets hash the password with SHA1,
+        /// NTLM datasets hash it with NTLM.
+        #[arg(
+            long,
+            default_value = "./hibp_password_hashes.txt",
+            verbatim_doc_comment
+        )]
+        dataset: PathBuf,
+        /// Read passwords from stdin (one per line) and print one count per line.
+        /// This is the default when stdin is not a terminal, otherwise
+        /// a single password is prompted for without echo.
+        #[arg(long, verbatim_doc_comment)]
+        batch: bool,
+    },
+    /// Write the prefix offset index (${DATASET}.idx) for a single file dataset.
+    /// Lookups use it to seek straight to a prefix. The downloader writes
+    /// one automatically, so this is only needed for older downloads.
+    #[command(name = "index", verbatim_doc_comment)]
+    Index {
+        /// The single file dataset to index.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+    },
+    /// Convert a text or directory dataset into the packed binary format.
+    /// Packed datasets are around half the size, and lookups on them are
+    /// done on a memory map instead of reading the file.
+    #[command(name = "pack", verbatim_doc_comment)]
+    Pack {
+        /// The dataset to convert.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// The file where the packed dataset will be written.
+        #[arg(long, default_value = "./hibp_password_hashes.bin")]
+        output_file: PathBuf,
+        /// Leave out hashes seen fewer than this many times.
+        #[arg(long, default_value_t = 1)]
+        min_count: u32,
+    },
+    /// Write a packed dataset that only stores the first bytes of each hash,
+    /// to look hashes up in a fraction of the space. Hashes that start with the
+    /// same bytes are merged, so lookups can match a hash that was never seen.
+    /// Prints the collisions and the false positive rate it measured as JSON.
+    #[command(name = "truncate", verbatim_doc_comment)]
+    Truncate {
+        /// The dataset to truncate, in any format.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// The file where the truncated packed dataset will be written.
+        #[arg(long, default_value = "./hibp_password_hashes_truncated.bin")]
+        output_file: PathBuf,
+        /// How many bytes of each hash to keep (3 to 20 for SHA1, 3 to 16 for NTLM).
+        /// Each row takes this + 2 bytes. Every byte dropped makes false positives
+        /// around 256 times more likely.
+        #[arg(long, default_value_t = 6, verbatim_doc_comment)]
+        hash_bytes: usize,
+        /// How many random hashes to look up in the output to measure
+        /// the false positive rate.
+        #[arg(long, default_value_t = 1_000_000, verbatim_doc_comment)]
+        samples: u64,
+        /// Leave out hashes seen fewer than this many times before merging.
+        #[arg(long, default_value_t = 1)]
+        min_count: u32,
     },
+    /// Build or query a Bloom filter of a dataset's hashes.
+    /// The filter says whether a hash was seen (without the count)
+    /// in a small fraction of the dataset's size.
+    #[command(name = "filter", verbatim_doc_comment)]
+    Filter {
+        #[command(subcommand)]
+        command: FilterCommands,
+    },
+    /// Audit stored password hashes against a downloaded dataset.
+    /// Reports accounts whose password was seen in a breach, accounts that
+    /// share their password with another account, and passwords that are
+    /// salted (so can't be checked) or stored in plaintext.
+    #[command(name = "audit", verbatim_doc_comment)]
+    Audit {
+        /// The file to audit, or "-" for stdin.
+        #[arg(long, default_value = "-")]
+        input_file: PathBuf,
+        /// The format of the input file.
+        /// pwdump: user:rid:lmhash:nthash::: lines (pwdump or secretsdump
+        ///         output of an NTDS extract), checked against an NTLM dataset.
+        /// ldif: userPassword attributes of an LDIF export, checked against a SHA1 dataset.
+        /// htpasswd: user:password lines, checked against a SHA1 dataset.
+        /// Only {SHA} passwords (and plaintext in LDIF) can be checked.
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = AuditInput::Pwdump,
+            verbatim_doc_comment
+        )]
+        input_format: AuditInput,
+        /// The NTLM (for pwdump) or SHA1 (for ldif and htpasswd) dataset to check against.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// Write the report to this file instead of stdout.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// The format of the report.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Csv)]
+        report_format: ReportFormat,
+        /// Leave the hashes out of the report.
+        /// Shared passwords are still grouped by number.
+        #[arg(long, verbatim_doc_comment)]
+        redact: bool,
+    },
+    /// Check hashes against the online range API without downloading everything.
+    /// Each needed prefix is fetched once and cached, and the suffixes are matched
+    /// locally. Prints ${HASH}:${COUNT} for every hash (0 if never seen).
+    #[command(name = "check-online", verbatim_doc_comment)]
+    CheckOnline {
+        /// A file with one SHA1 or NTLM hash (in hex) per line, or "-" for stdin.
+        #[arg(long, default_value = "-")]
+        input_file: PathBuf,
+        /// Write the results to this file instead of stdout.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// Range responses are cached in this directory, so repeated
+        /// checks don't fetch the same prefixes again.
+        #[arg(long, default_value = "./hibp_range_cache", verbatim_doc_comment)]
+        cache_dir: PathBuf,
+        /// How many seconds a cached range response is used for.
+        #[arg(long, default_value_t = 24 * 60 * 60)]
+        cache_ttl: u64,
+        /// The number of range requests in flight at once.
+        #[arg(
+            long,
+            default_value_t = 32,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        concurrent_requests: usize,
+        /// Request this many random decoy prefixes for every real prefix.
+        /// They are shuffled in with the real requests and handled the same
+        /// way, so the traffic reveals much less about which hashes are held.
+        #[arg(long, default_value_t = 0, verbatim_doc_comment)]
+        decoys: usize,
+        /// Send the Add-Padding header, so each response is padded with fake
+        /// ${SUFFIX}:0 rows and its size doesn't reveal the prefix.
+        #[arg(long, verbatim_doc_comment)]
+        add_padding: bool,
+    },
+    /// Serve the k-anonymity range API from local datasets, the same way
+    /// api.pwnedpasswords.com does. GET /range/${PREFIX} answers from the SHA1
+    /// dataset, and GET /range/${PREFIX}?mode=ntlm from the NTLM dataset.
+    /// POST /check takes {"hashes": [...]} and answers with the count of each,
+    /// and GET /health describes the datasets (including their manifests).
+    #[command(name = "serve", verbatim_doc_comment)]
+    Serve {
+        /// A dataset to serve, in any format. Give it twice to serve
+        /// both a SHA1 and an NTLM dataset. Text files without an .idx
+        /// get their prefix index built in memory when starting.
+        #[arg(
+            long,
+            default_value = "./hibp_password_hashes.txt",
+            verbatim_doc_comment
+        )]
+        dataset: Vec<PathBuf>,
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// The most hashes a single POST /check request can hold.
+        #[arg(long, default_value_t = 1000)]
+        max_hashes: usize,
+        /// The largest POST /check body accepted, in bytes.
+        #[arg(long, default_value_t = 1024 * 1024)]
+        max_body_bytes: usize,
+    },
+    /// Check a large list of hashes against a downloaded dataset in one pass.
+    /// Prints ${HASH}:${COUNT} for every hash that was found.
+    #[command(name = "bulk-check", verbatim_doc_comment)]
+    BulkCheck {
+        /// A file with one SHA1 or NTLM hash (in hex) per line, or "-" for stdin.
+        #[arg(long, default_value = "-")]
+        input_file: PathBuf,
+        /// The dataset to search. Either a single file or a directory
+        /// written by the downloader.
+        #[arg(
+            long,
+            default_value = "./hibp_password_hashes.txt",
+            verbatim_doc_comment
+        )]
+        dataset: PathBuf,
+        /// Write the matches to this file instead of stdout.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// This directory will be used to store the temporary files
+        /// used to sort the input hashes. It will be deleted upon completion.
+        #[arg(
+            long,
+            default_value = "./tmp_scratch_disk_for_hibp_bulk_check",
+            verbatim_doc_comment
+        )]
+        temp_dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum FilterCommands {
+    /// Build a Bloom filter from a dataset in any format.
+    #[command(name = "build")]
+    Build {
+        /// The dataset to read the hashes from.
+        #[arg(long, default_value = "./hibp_password_hashes.txt")]
+        dataset: PathBuf,
+        /// The file where the filter will be written.
+        #[arg(long, default_value = "./hibp_password_hashes.bloom")]
+        output_file: PathBuf,
+        /// The false positive rate the filter is sized for.
+        /// Halving it costs around 1.44 more bits per hash.
+        #[arg(long, default_value_t = 0.001, verbatim_doc_comment)]
+        fp_rate: f64,
+        /// Only add hashes seen at least this many times.
+        #[arg(long, default_value_t = 1)]
+        min_count: u32,
+    },
+    /// Check whether a hash is in a Bloom filter.
+    /// Prints "not found" (certain) or "found" (which may be a false positive).
+    #[command(name = "check", verbatim_doc_comment)]
+    Check {
+        /// The SHA1 or NTLM hash (in hex) to check.
+        /// If omitted or "-", hashes are read from stdin (one per line)
+        /// and each is printed as ${HASH}:found or ${HASH}:not found.
+        #[arg(verbatim_doc_comment)]
+        hash: Option<String>,
+        /// The filter written by filter build.
+        #[arg(long, default_value = "./hibp_password_hashes.bloom")]
+        filter: PathBuf,
+    },
+}
+
+/// A number strictly between 0 and 1.
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let value = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if value > 0.0 && value < 1.0 {
+        Ok(value)
+    } else {
+        Err(format!("{value} is not between 0 and 1"))
+    }
 }
 
 fn default_workers() -> usize {
@@ -0,0 +1,244 @@
+//! The Bloom filter file format.
+//!
+//! All integers are little endian.
+//!
+//! ```text
+//! Header (32 bytes)
+//!   magic        8 bytes  "HIBPBLOM"
+//!   version      u16      1
+//!   mode         u8       0 = SHA1, 1 = NTLM
+//!   hash_count   u8       Bits set per hash (k)
+//!   min_count    u32      Only hashes seen at least this many times were added
+//!   bit_count    u64      Size of the bit array (m)
+//!   item_count   u64      Hashes added (n)
+//! Bits (ceil(m / 64) x u64)
+//!   Bit i is bit (i % 64) of word (i / 64)
+//! ```
+//!
+//! The hashes are already uniformly distributed, so no further hashing is done.
+//! With `h1` and `h2` the first and second 8 bytes of the hash (big endian, and `h2`
+//! made odd), the bits of a hash are `(h1 + i * h2) mod m` for `i` in `0..k`
+//! (with wrapping u64 arithmetic).
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use memmap2::Mmap;
+
+use super::{decode_hex, map_file, HashMode};
+
+pub const MAGIC: &[u8; 8] = b"HIBPBLOM";
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 32;
+
+/// A Bloom filter of the hashes in a dataset.
+///
+/// It answers whether a hash was seen without the counts, in a fraction of the space.
+/// A hash that was added is always found, but a hash that wasn't may also be found
+/// (with the false positive rate the filter was sized for).
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    mode: HashMode,
+    hash_count: u8,
+    min_count: u32,
+    bit_count: u64,
+    item_count: u64,
+    bits: Bits,
+}
+
+/// The bit array, either built in memory or mapped from a filter file
+/// (so checking a hash doesn't read the whole file).
+#[derive(Debug, Clone)]
+enum Bits {
+    Owned(Vec<u64>),
+    /// The whole file, with the words after the header.
+    Mapped(Arc<Mmap>),
+}
+
+impl Bits {
+    fn words(&self) -> usize {
+        match self {
+            Bits::Owned(words) => words.len(),
+            Bits::Mapped(mmap) => (mmap.len() - HEADER_LEN) / 8,
+        }
+    }
+
+    fn word(&self, i: usize) -> u64 {
+        match self {
+            Bits::Owned(words) => words[i],
+            Bits::Mapped(mmap) => {
+                let start = HEADER_LEN + i * 8;
+                u64::from_le_bytes(mmap[start..start + 8].try_into().unwrap())
+            }
+        }
+    }
+
+    /// Copies a mapped filter into memory so it can be added to.
+    fn to_mut(&mut self) -> &mut Vec<u64> {
+        if let Bits::Mapped(_) = self {
+            *self = Bits::Owned((0..self.words()).map(|i| self.word(i)).collect());
+        }
+        match self {
+            Bits::Owned(words) => words,
+            Bits::Mapped(_) => unreachable!(),
+        }
+    }
+}
+
+impl BloomFilter {
+    /// An empty filter sized to hold `capacity` hashes with a false positive rate
+    /// of `fp_rate` (between 0 and 1).
+    pub fn new(
+        mode: HashMode,
+        capacity: u64,
+        fp_rate: f64,
+        min_count: u32,
+    ) -> anyhow::Result<Self> {
+        if !(fp_rate > 0.0 && fp_rate < 1.0) {
+            anyhow::bail!("The false positive rate must be between 0 and 1, not {fp_rate}");
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let capacity = capacity.max(1) as f64;
+        let bit_count = (-capacity * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let hash_count = (bit_count as f64 / capacity * ln2).round().clamp(1.0, 32.0) as u8;
+        Ok(Self {
+            mode,
+            hash_count,
+            min_count,
+            bit_count,
+            item_count: 0,
+            bits: Bits::Owned(vec![0; bit_count.div_ceil(64) as usize]),
+        })
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mmap = map_file(&file)?;
+        if mmap.len() < HEADER_LEN || &mmap[..8] != MAGIC {
+            anyhow::bail!("{} is not a Bloom filter", path.display());
+        }
+        let header = &mmap[..HEADER_LEN];
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            anyhow::bail!("Unsupported Bloom filter version {version}");
+        }
+        let mode = match header[10] {
+            0 => HashMode::Sha1,
+            1 => HashMode::Ntlm,
+            other => anyhow::bail!("Unknown Bloom filter mode {other}"),
+        };
+        let hash_count = header[11];
+        let min_count = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let bit_count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let item_count = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        if hash_count == 0 || bit_count == 0 {
+            anyhow::bail!("{} has an empty Bloom filter header", path.display());
+        }
+
+        let bytes = (mmap.len() - HEADER_LEN) as u64;
+        if bytes != bit_count.div_ceil(64) * 8 {
+            anyhow::bail!(
+                "{} is truncated or corrupt ({bytes} bytes of bits, expected {})",
+                path.display(),
+                bit_count.div_ceil(64) * 8
+            );
+        }
+        Ok(Self {
+            mode,
+            hash_count,
+            min_count,
+            bit_count,
+            item_count,
+            bits: Bits::Mapped(Arc::new(mmap)),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::with_capacity(1024 * 1024, file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[
+            match self.mode {
+                HashMode::Sha1 => 0,
+                HashMode::Ntlm => 1,
+            },
+            self.hash_count,
+        ])?;
+        writer.write_all(&self.min_count.to_le_bytes())?;
+        writer.write_all(&self.bit_count.to_le_bytes())?;
+        writer.write_all(&self.item_count.to_le_bytes())?;
+        for i in 0..self.bits.words() {
+            writer.write_all(&self.bits.word(i).to_le_bytes())?;
+        }
+        writer.into_inner()?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn mode(&self) -> HashMode {
+        self.mode
+    }
+
+    /// Only hashes seen at least this many times were added.
+    pub fn min_count(&self) -> u32 {
+        self.min_count
+    }
+
+    /// How many hashes were added.
+    pub fn len(&self) -> u64 {
+        self.item_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.item_count == 0
+    }
+
+    /// The size of the bit array, in bytes.
+    pub fn size_bytes(&self) -> u64 {
+        self.bits.words() as u64 * 8
+    }
+
+    /// The expected false positive rate for the hashes added so far.
+    pub fn false_positive_rate(&self) -> f64 {
+        let k = f64::from(self.hash_count);
+        let fill = 1.0 - (-k * self.item_count as f64 / self.bit_count as f64).exp();
+        fill.powf(k)
+    }
+
+    /// Adds a hash (hex of either case, of the same mode as the filter).
+    pub fn insert(&mut self, hash: &str) -> anyhow::Result<()> {
+        let positions = self.bit_positions(hash)?;
+        let words = self.bits.to_mut();
+        for bit in positions {
+            words[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.item_count += 1;
+        Ok(())
+    }
+
+    /// Returns `false` if the hash was definitely not added, and `true` if it
+    /// probably was. `hash` is hex (of either case) of the same mode as the filter.
+    pub fn contains(&self, hash: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .bit_positions(hash)?
+            .all(|bit| self.bits.word((bit / 64) as usize) & (1 << (bit % 64)) != 0))
+    }
+
+    fn bit_positions(&self, hash: &str) -> anyhow::Result<impl Iterator<Item = u64>> {
+        let bytes = decode_hex(hash)
+            .filter(|b| b.len() * 2 == self.mode.hex_len())
+            .with_context(|| format!("{hash:?} is not a {} hash", self.mode.name()))?;
+        let h1 = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) | 1;
+        let bit_count = self.bit_count;
+        Ok((0..u64::from(self.hash_count))
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count))
+    }
+}
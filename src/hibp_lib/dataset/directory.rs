@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use super::{
+    super::consts::{BEGIN, END},
+    split_row, HashMode, Row,
+};
+
+/// The directory format holds the responses as-is, one file per prefix,
+/// so each row is missing the first 5 characters of the hash.
+/// The mode comes from the first prefix file there is, since any could have failed to download.
+pub fn detect_mode(dir: &Path) -> anyhow::Result<HashMode> {
+    for n in BEGIN..=END {
+        let prefix = format!("{n:05X}");
+        let text = match std::fs::read_to_string(dir.join(&prefix)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let Some((suffix, _)) = text.lines().find_map(split_row) else {
+            continue;
+        };
+        return HashMode::from_hex_len(suffix.len() + 5)
+            .with_context(|| format!("Unknown hash length in the {prefix} prefix file"));
+    }
+    anyhow::bail!("{} has no prefix files with rows", dir.display())
+}
+
+/// A missing prefix file (ie. from a partially failed download) has no rows.
+pub fn count(dir: &Path, hash: &str) -> anyhow::Result<Option<u32>> {
+    let (prefix, suffix) = hash.split_at(5);
+    let text = match std::fs::read_to_string(dir.join(prefix)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(text
+        .lines()
+        .filter_map(split_row)
+        .find(|&(row_suffix, _)| row_suffix.eq_ignore_ascii_case(suffix))
+        .map(|(_, count)| count))
+}
+
+/// Likewise, a missing prefix file has no rows.
+pub fn range(dir: &Path, n: u32) -> anyhow::Result<Vec<Row>> {
+    let prefix = format!("{n:05X}");
+    let text = match std::fs::read_to_string(dir.join(&prefix)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (suffix, count) =
+                split_row(line).with_context(|| format!("Invalid row in prefix file {prefix}"))?;
+            Ok(Row {
+                hash: format!("{prefix}{}", suffix.to_ascii_uppercase()),
+                count,
+            })
+        })
+        .collect()
+}
+
+pub fn rows(dir: &Path) -> impl Iterator<Item = anyhow::Result<Row>> {
+    let dir = dir.to_path_buf();
+    (BEGIN..=END).flat_map(move |n| match range(&dir, n) {
+        Ok(rows) => rows.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    })
+}
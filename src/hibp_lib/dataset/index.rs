@@ -0,0 +1,111 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use super::super::consts::LENGTH;
+
+/// The prefix offset index is a `.idx` sidecar next to a single file dataset.
+///
+/// It is a table of 1,048,576 little endian u64 byte offsets, one per prefix.
+/// Entry `n` is where the rows for prefix `n` start. A prefix's rows end where the
+/// next prefix's start (or at the end of the file for FFFFF), so a prefix without
+/// rows has the same offset as the next one.
+pub struct PrefixIndex {
+    offsets: Vec<u64>,
+}
+
+/// `hibp_password_hashes.txt` -> `hibp_password_hashes.txt.idx`
+pub fn index_path(dataset: &Path) -> PathBuf {
+    let mut path = dataset.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+impl PrefixIndex {
+    /// Prefixes missing from `offsets` (`None`) are given the offset of the next prefix.
+    pub fn from_offsets(offsets: &[Option<u64>], file_len: u64) -> Self {
+        let mut next = file_len;
+        let mut filled = vec![0; LENGTH as usize];
+        for (n, offset) in offsets.iter().enumerate().rev() {
+            next = offset.unwrap_or(next);
+            filled[n] = next;
+        }
+        Self { offsets: filled }
+    }
+
+    /// Returns `None` if the dataset has no index.
+    pub fn load(dataset: &Path, file_len: u64) -> anyhow::Result<Option<Self>> {
+        let path = index_path(dataset);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if bytes.len() != LENGTH as usize * 8 {
+            anyhow::bail!("{} is not a prefix index", path.display());
+        }
+        let offsets = bytes
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        if offsets.windows(2).any(|w| w[0] > w[1]) || offsets[offsets.len() - 1] > file_len {
+            anyhow::bail!(
+                "{} does not match the dataset. Rebuild it with the index subcommand.",
+                path.display()
+            );
+        }
+        Ok(Some(Self { offsets }))
+    }
+
+    /// Scans a single file dataset for the start of each prefix.
+    pub fn build(dataset: &Path, mut on_progress: impl FnMut(u64)) -> anyhow::Result<Self> {
+        let file = File::open(dataset)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::with_capacity(16 * 1024 * 1024, file);
+        let mut offsets = vec![None; LENGTH as usize];
+        let mut offset = 0;
+        let mut last_prefix = None;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let len = reader.read_until(b'\n', &mut line)? as u64;
+            if len == 0 {
+                break;
+            }
+            let prefix = std::str::from_utf8(line.get(..5).unwrap_or_default())
+                .ok()
+                .and_then(|p| u32::from_str_radix(p, 16).ok())
+                .with_context(|| format!("Invalid row at byte {offset}"))?;
+            if last_prefix != Some(prefix) {
+                if last_prefix.is_some_and(|last| last > prefix) {
+                    anyhow::bail!("The dataset is not sorted by prefix (at byte {offset})");
+                }
+                offsets[prefix as usize] = Some(offset);
+                last_prefix = Some(prefix);
+            }
+            offset += len;
+            on_progress(len);
+        }
+        Ok(Self::from_offsets(&offsets, file_len))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.offsets.iter().flat_map(|o| o.to_le_bytes()).collect()
+    }
+
+    /// The byte range holding the rows for `prefix`.
+    pub fn range(&self, prefix: u32, file_len: u64) -> Range<u64> {
+        let start = self.offsets[prefix as usize];
+        let end = self
+            .offsets
+            .get(prefix as usize + 1)
+            .copied()
+            .unwrap_or(file_len);
+        start..end
+    }
+}
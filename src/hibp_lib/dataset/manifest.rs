@@ -0,0 +1,146 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{Format, HashMode};
+
+/// The manifest is a `.manifest.json` sidecar next to a dataset, describing
+/// where it came from so that consumers can tell which version they are using.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The version of hibp_downloader that wrote the dataset.
+    pub tool_version: String,
+    pub mode: HashMode,
+    pub format: Format,
+    /// Unix time (in seconds) when the download started.
+    pub download_started: u64,
+    /// Unix time (in seconds) when the download finished.
+    pub download_finished: u64,
+    pub rows: u64,
+    /// Only hashes seen at least this many times were written (`--min-count`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_count: Option<u32>,
+    /// Rows left out for being under `min_count`.
+    #[serde(default)]
+    pub rows_dropped: u64,
+    /// Prefixes that failed to download and are missing from the dataset.
+    pub failed_prefixes: Vec<String>,
+    /// Prefixes that still had far fewer rows than in `--previous-manifest` after
+    /// retrying, but were kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anomalous_prefixes: Vec<String>,
+    /// The rows downloaded for each prefix (before `--min-count`), so the next
+    /// download can spot prefixes that lost rows. In the JSON this is base64 of
+    /// 1,048,576 little endian u32s.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "prefix_rows_base64"
+    )]
+    pub prefix_rows: Vec<u32>,
+    /// The checksum of the dataset as it was written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// `hibp_password_hashes.txt` -> `hibp_password_hashes.txt.manifest.json`
+///
+/// It always goes next to the dataset, so `dir/` (or `.`) gets `dir.manifest.json`
+/// rather than a file inside the directory.
+pub fn manifest_path(dataset: &Path) -> PathBuf {
+    let dataset = match dataset.file_name() {
+        Some(_) => dataset.to_path_buf(),
+        None => dataset
+            .canonicalize()
+            .unwrap_or_else(|_| dataset.to_path_buf()),
+    };
+    let Some(name) = dataset.file_name() else {
+        // The root directory has nothing to be next to
+        return dataset.join(".manifest.json");
+    };
+    let mut name = name.to_owned();
+    name.push(".manifest.json");
+    dataset.with_file_name(name)
+}
+
+impl Manifest {
+    /// Returns `None` if the dataset has no manifest.
+    pub fn load(dataset: &Path) -> anyhow::Result<Option<Self>> {
+        match Self::read(&manifest_path(dataset)) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the manifest file itself (not the dataset it describes).
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("{} is not a valid manifest", path.display()))
+    }
+
+    /// The manifest for a packed file written from this dataset, with `rows` kept
+    /// and `rows_dropped` more left out for being under `min_count`.
+    pub fn packed(&self, rows: u64, min_count: u32, rows_dropped: u64) -> Self {
+        Self {
+            format: Format::Packed,
+            rows,
+            min_count: self.min_count.max((min_count > 1).then_some(min_count)),
+            rows_dropped: self.rows_dropped + rows_dropped,
+            checksum: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn write(&self, dataset: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(manifest_path(dataset), json + "\n")?;
+        Ok(())
+    }
+}
+
+/// Seconds since the Unix epoch, as the manifest stores times.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Stores `prefix_rows` as one base64 string instead of a million JSON numbers.
+mod prefix_rows_base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(rows: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = rows
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect::<Vec<_>>();
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+        let bytes = STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(D::Error::custom)?;
+        if !bytes.len().is_multiple_of(4) {
+            return Err(D::Error::custom(
+                "prefix_rows is not a whole number of u32s",
+            ));
+        }
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+}
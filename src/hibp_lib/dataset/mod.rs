@@ -0,0 +1,249 @@
+pub mod bloom;
+mod directory;
+pub mod index;
+pub mod manifest;
+pub mod packed;
+mod text_file;
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use super::consts::END;
+use index::PrefixIndex;
+use manifest::Manifest;
+use packed::PackedFile;
+
+/// A row of a dataset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    /// The full hash, in uppercase hex.
+    /// Truncated datasets only store the start of each hash (see [`Dataset::truncated`]).
+    pub hash: String,
+    /// How many times the password was seen in breaches.
+    pub count: u32,
+}
+
+/// An iterator over the rows of a dataset, in hash order.
+pub type Rows = Box<dyn Iterator<Item = anyhow::Result<Row>> + Send>;
+
+/// The kind of hashes a dataset holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashMode {
+    Sha1,
+    Ntlm,
+}
+
+impl HashMode {
+    /// Guesses the mode from the length of a hex hash.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            40 => Some(HashMode::Sha1),
+            32 => Some(HashMode::Ntlm),
+            _ => None,
+        }
+    }
+
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashMode::Sha1 => 40,
+            HashMode::Ntlm => 32,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashMode::Sha1 => "SHA1",
+            HashMode::Ntlm => "NTLM",
+        }
+    }
+}
+
+/// Validates a hex SHA1 or NTLM hash, returning its mode and its uppercase form
+/// (which is how the hashes are stored).
+pub fn parse_hash(hash: &str) -> anyhow::Result<(HashMode, String)> {
+    let hash = hash.trim();
+    let mode = HashMode::from_hex_len(hash.len())
+        .with_context(|| format!("{hash:?} is not the length of a SHA1 or NTLM hash"))?;
+    if !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("{hash:?} is not a hex string");
+    }
+    Ok((mode, hash.to_ascii_uppercase()))
+}
+
+/// A dataset written by the downloader, in any of its formats:
+/// a single text file (with or without its `.idx` prefix index),
+/// a directory of prefix files, or a packed binary file.
+///
+/// The format and hash mode are detected when it is opened. A `Dataset` can be
+/// shared between threads, and lookups on a packed dataset are done on a memory map.
+pub struct Dataset {
+    path: PathBuf,
+    mode: HashMode,
+    reader: Reader,
+    manifest: Option<Manifest>,
+}
+
+/// How a dataset is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A single text file of `HASH:COUNT` rows.
+    Text,
+    /// A directory with one file of `SUFFIX:COUNT` rows per prefix.
+    Directory,
+    /// A single file in the packed binary format.
+    Packed,
+}
+
+enum Reader {
+    /// The single file, with its prefix offset index if it has one.
+    Text(Option<PrefixIndex>),
+    Directory,
+    Packed(PackedFile),
+}
+
+impl Dataset {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to open dataset {}", path.display()))?;
+        let (mode, reader) = if metadata.is_dir() {
+            (directory::detect_mode(path)?, Reader::Directory)
+        } else if packed::is_packed(path)? {
+            let packed = PackedFile::open(path)?;
+            (packed.mode(), Reader::Packed(packed))
+        } else {
+            (
+                text_file::detect_mode(path)?,
+                Reader::Text(PrefixIndex::load(path, metadata.len())?),
+            )
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode,
+            reader,
+            manifest: Manifest::load(path)?,
+        })
+    }
+
+    pub fn mode(&self) -> HashMode {
+        self.mode
+    }
+
+    pub fn format(&self) -> Format {
+        match self.reader {
+            Reader::Text(_) => Format::Text,
+            Reader::Directory => Format::Directory,
+            Reader::Packed(_) => Format::Packed,
+        }
+    }
+
+    /// For a truncated packed dataset, how many bytes of each hash it stores.
+    /// Lookups in it still take the full hash, but may match a different hash
+    /// that starts with the same bytes.
+    pub fn truncated(&self) -> Option<usize> {
+        match &self.reader {
+            Reader::Packed(packed) if packed.hash_bytes() < self.mode.hex_len() / 2 => {
+                Some(packed.hash_bytes())
+            }
+            _ => None,
+        }
+    }
+
+    /// Errors for truncated datasets, for commands that need every row's full hash.
+    pub(crate) fn ensure_not_truncated(&self) -> anyhow::Result<()> {
+        match self.truncated() {
+            Some(bytes) => anyhow::bail!(
+                "{} is truncated to {bytes} bytes per hash, which this command can't use",
+                self.path.display()
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// The manifest written alongside the dataset, if it has one.
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Builds the prefix index in memory for a single text file without an `.idx` sidecar,
+    /// so lookups don't have to binary search the whole file.
+    /// The other formats always have their index.
+    pub fn build_index(&mut self) -> anyhow::Result<()> {
+        if let Reader::Text(index @ None) = &mut self.reader {
+            *index = Some(PrefixIndex::build(&self.path, |_| {})?);
+        }
+        Ok(())
+    }
+
+    /// Returns how many times the hash was seen, or `None` if it isn't in the dataset.
+    /// `hash` is hex (of either case) of the same mode as the dataset.
+    pub fn count(&self, hash: &str) -> anyhow::Result<Option<u32>> {
+        if hash.len() != self.mode.hex_len() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("{hash:?} is not a {} hash", self.mode.name());
+        }
+        let hash = hash.to_ascii_uppercase();
+        match &self.reader {
+            Reader::Text(index) => text_file::count(&self.path, index.as_ref(), &hash),
+            Reader::Directory => directory::count(&self.path, &hash),
+            Reader::Packed(packed) => Ok(packed.count(&hash)),
+        }
+    }
+
+    /// Returns every row whose hash starts with the 5 hex character `prefix`
+    /// (0x00000 to 0xFFFFF), in hash order.
+    pub fn range(&self, prefix: u32) -> anyhow::Result<Vec<Row>> {
+        if prefix > END {
+            anyhow::bail!("Prefix 0x{prefix:X} is more than 5 hex characters");
+        }
+        match &self.reader {
+            Reader::Text(index) => text_file::range(&self.path, index.as_ref(), prefix),
+            Reader::Directory => directory::range(&self.path, prefix),
+            Reader::Packed(packed) => Ok(packed.range(prefix).collect()),
+        }
+    }
+
+    /// Iterates over every row, in hash order.
+    pub fn rows(&self) -> anyhow::Result<Rows> {
+        match &self.reader {
+            Reader::Text(_) => text_file::rows(&self.path),
+            Reader::Directory => Ok(Box::new(directory::rows(&self.path))),
+            Reader::Packed(packed) => Ok(Box::new(packed.rows())),
+        }
+    }
+}
+
+/// Decodes a hex string (of either case) into bytes.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Encodes bytes as uppercase hex, which is how hashes are stored.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+/// Maps a packed dataset or Bloom filter read only.
+fn map_file(file: &File) -> std::io::Result<Mmap> {
+    // SAFETY: The map is read only. Like any mmap, modifying the file while it
+    // is open is undefined behaviour, which is the same for every other reader.
+    unsafe { Mmap::map(file) }
+}
+
+/// Splits a `HASH:COUNT` row, ignoring any trailing `\r`.
+pub fn split_row(line: &str) -> Option<(&str, u32)> {
+    let (hash, count) = line.trim_end().split_once(':')?;
+    Some((hash, count.parse().ok()?))
+}
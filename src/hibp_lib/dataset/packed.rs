@@ -0,0 +1,329 @@
+//! The packed binary dataset format.
+//!
+//! All integers are little endian.
+//!
+//! ```text
+//! Header (32 bytes)
+//!   magic      8 bytes  "HIBPPACK"
+//!   version    u16      1
+//!   mode       u8       0 = SHA1, 1 = NTLM
+//!   key_len    u8       Bytes of each hash stored per row (see below)
+//!   reserved   4 bytes
+//!   row_count  u64
+//!   reserved   8 bytes
+//! Prefix index (1,048,577 x u64)
+//!   Entry n is the first row of prefix n. The last entry is row_count.
+//! Rows (row_count x (key_len + 4) bytes), in hash order
+//!   key        key_len bytes  The hash, starting from its 3rd byte
+//!   count      u32
+//! ```
+//!
+//! The first 2 bytes of the hash are implied by the prefix, so the key starts at the
+//! byte holding the 5th and 6th hex characters. For a full dataset key_len is 18 for
+//! SHA1 and 14 for NTLM (22 and 18 bytes per row instead of 45+ as text).
+//!
+//! A truncated dataset (written by the `truncate` subcommand) has a shorter key_len,
+//! so each row only holds the first `key_len + 2` bytes of its hash. Hashes that share
+//! those bytes are merged into one row with the sum of their counts.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use memmap2::Mmap;
+
+use super::{
+    super::{
+        consts::{BEGIN, END, LENGTH},
+        exit_status::{ExitStatus, Failure},
+    },
+    decode_hex, encode_hex, map_file, HashMode, Row,
+};
+
+pub const MAGIC: &[u8; 8] = b"HIBPPACK";
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 32;
+const INDEX_LEN: usize = (LENGTH as usize + 1) * 8;
+/// Where the rows start, after the header and the prefix index.
+pub const DATA_OFFSET: usize = HEADER_LEN + INDEX_LEN;
+/// The hash bytes before the stored key, which are implied by the prefix.
+const KEY_START: usize = 2;
+
+/// The key length that stores the whole hash.
+pub fn full_key_len(mode: HashMode) -> usize {
+    mode.hex_len() / 2 - KEY_START
+}
+
+fn prefix_of(hash: &[u8]) -> u32 {
+    (u32::from(hash[0]) << 12) | (u32::from(hash[1]) << 4) | (u32::from(hash[2]) >> 4)
+}
+
+/// Encodes rows into the packed format.
+///
+/// The rows are written first (starting at `DATA_OFFSET`), and the header and
+/// prefix index are written over the start of the file once the rows are done.
+pub struct PackedEncoder {
+    mode: HashMode,
+    key_len: usize,
+    starts: Vec<Option<u64>>,
+    row_count: u64,
+    /// The last hash encoded, to check the rows are in order.
+    last: Option<Vec<u8>>,
+}
+
+impl PackedEncoder {
+    pub fn new(mode: HashMode, key_len: usize) -> Self {
+        Self {
+            mode,
+            key_len,
+            starts: vec![None; LENGTH as usize],
+            row_count: 0,
+            last: None,
+        }
+    }
+
+    /// Appends the encoded row to `out`. Rows must be given in hash order,
+    /// otherwise the prefix index would point at the wrong rows.
+    pub fn encode_row(&mut self, hash: &str, count: u32, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let bytes = decode_hex(hash)
+            .filter(|b| b.len() * 2 == self.mode.hex_len())
+            .with_context(|| format!("{hash:?} is not a {} hash", self.mode.name()))?;
+        if let Some(last) = self.last.as_ref().filter(|last| bytes <= **last) {
+            return Err(Failure::new(
+                ExitStatus::ValidationFailure,
+                format!(
+                    "The rows are not in hash order ({hash} after {}). \
+                     Frequency sorted files can't be packed.",
+                    encode_hex(last)
+                ),
+            )
+            .into());
+        }
+        self.starts[prefix_of(&bytes) as usize].get_or_insert(self.row_count);
+        out.extend_from_slice(&bytes[KEY_START..KEY_START + self.key_len]);
+        out.extend_from_slice(&count.to_le_bytes());
+        self.row_count += 1;
+        self.last = Some(bytes);
+        Ok(())
+    }
+
+    /// The header and prefix index, which go at the start of the file.
+    pub fn header_and_index(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(DATA_OFFSET);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.push(match self.mode {
+            HashMode::Sha1 => 0,
+            HashMode::Ntlm => 1,
+        });
+        out.push(self.key_len as u8);
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&self.row_count.to_le_bytes());
+        out.extend_from_slice(&[0; 8]);
+
+        // Prefixes without rows start where the next prefix starts
+        let mut index = vec![self.row_count; LENGTH as usize + 1];
+        let mut next = self.row_count;
+        for (n, start) in self.starts.iter().enumerate().rev() {
+            next = start.unwrap_or(next);
+            index[n] = next;
+        }
+        out.extend(index.iter().flat_map(|i| i.to_le_bytes()));
+        out
+    }
+}
+
+/// Writes a packed file: room for the header and index first, then the rows as
+/// they come, and the header and index over the start of the file once they're known.
+pub struct PackedWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    encoder: PackedEncoder,
+    buf: Vec<u8>,
+}
+
+impl PackedWriter {
+    pub fn create(path: &Path, mode: HashMode, key_len: usize) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::with_capacity(1024 * 1024 * 32, file);
+        writer.write_all(&vec![0; DATA_OFFSET])?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            encoder: PackedEncoder::new(mode, key_len),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Rows must be given in hash order. Returns the bytes written.
+    /// A row that can't be encoded removes the file, since it can never be finished.
+    pub fn write_row(&mut self, hash: &str, count: u32) -> anyhow::Result<usize> {
+        self.buf.clear();
+        if let Err(e) = self.encoder.encode_row(hash, count, &mut self.buf) {
+            std::fs::remove_file(&self.path).ok();
+            return Err(e);
+        }
+        self.writer.write_all(&self.buf)?;
+        Ok(self.buf.len())
+    }
+
+    /// Writes the header and index and syncs the file, returning it.
+    pub fn finish(self) -> anyhow::Result<File> {
+        let mut file = self.writer.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.encoder.header_and_index())?;
+        file.sync_all()?;
+        Ok(file)
+    }
+}
+
+/// Checks the magic bytes at the start of a file.
+pub fn is_packed(path: &Path) -> std::io::Result<bool> {
+    let mut magic = [0; 8];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A memory-mapped packed dataset.
+#[derive(Clone)]
+pub struct PackedFile {
+    mmap: Arc<Mmap>,
+    mode: HashMode,
+    key_len: usize,
+}
+
+impl PackedFile {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = map_file(&file)?;
+        if mmap.len() < DATA_OFFSET || &mmap[..8] != MAGIC {
+            anyhow::bail!("{} is not a packed dataset", path.display());
+        }
+        let version = u16::from_le_bytes([mmap[8], mmap[9]]);
+        if version != VERSION {
+            anyhow::bail!("Unsupported packed dataset version {version}");
+        }
+        let mode = match mmap[10] {
+            0 => HashMode::Sha1,
+            1 => HashMode::Ntlm,
+            other => anyhow::bail!("Unknown packed dataset mode {other}"),
+        };
+        let key_len = usize::from(mmap[11]);
+        let row_count = u64::from_le_bytes(mmap[16..24].try_into().unwrap());
+        if key_len == 0 || key_len > full_key_len(mode) {
+            anyhow::bail!("Invalid packed dataset key length {key_len}");
+        }
+        let expected_len = DATA_OFFSET as u64 + row_count * (key_len as u64 + 4);
+        if mmap.len() as u64 != expected_len {
+            anyhow::bail!(
+                "{} is truncated or corrupt ({} bytes, expected {expected_len})",
+                path.display(),
+                mmap.len()
+            );
+        }
+        Ok(Self {
+            mmap: Arc::new(mmap),
+            mode,
+            key_len,
+        })
+    }
+
+    pub fn mode(&self) -> HashMode {
+        self.mode
+    }
+
+    /// How many bytes of each hash are stored.
+    pub fn hash_bytes(&self) -> usize {
+        KEY_START + self.key_len
+    }
+
+    fn row_len(&self) -> usize {
+        self.key_len + 4
+    }
+
+    fn index_entry(&self, n: usize) -> u64 {
+        let at = HEADER_LEN + n * 8;
+        u64::from_le_bytes(self.mmap[at..at + 8].try_into().unwrap())
+    }
+
+    fn row(&self, i: u64) -> (&[u8], u32) {
+        let at = DATA_OFFSET + i as usize * self.row_len();
+        let (key, count) = self.mmap[at..at + self.row_len()].split_at(self.key_len);
+        (key, u32::from_le_bytes(count.try_into().unwrap()))
+    }
+
+    /// Rows of `prefix` are `first..last`.
+    fn prefix_rows(&self, prefix: u32) -> std::ops::Range<u64> {
+        self.index_entry(prefix as usize)..self.index_entry(prefix as usize + 1)
+    }
+
+    /// Interpolation search within the hash's prefix. The hashes are uniformly
+    /// distributed, so this usually lands on the row in 1 or 2 guesses.
+    /// `hash` must be valid hex of the same mode as the dataset.
+    pub fn count(&self, hash: &str) -> Option<u32> {
+        let bytes = decode_hex(hash)?;
+        let key = &bytes[KEY_START..KEY_START + self.key_len];
+        let target = key_value(key);
+        let std::ops::Range {
+            start: mut lo,
+            end: mut hi,
+        } = self.prefix_rows(prefix_of(&bytes));
+        while lo < hi {
+            let (lo_value, hi_value) = (key_value(self.row(lo).0), key_value(self.row(hi - 1).0));
+            if target < lo_value || target > hi_value {
+                return None;
+            }
+            let mid = if lo_value == hi_value {
+                lo + (hi - lo) / 2
+            } else {
+                let offset = u128::from(target - lo_value) * u128::from(hi - 1 - lo)
+                    / u128::from(hi_value - lo_value);
+                lo + offset as u64
+            };
+            let (row_key, count) = self.row(mid);
+            match row_key.cmp(key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(count),
+            }
+        }
+        None
+    }
+
+    /// Iterates over the rows of one prefix with the full hash
+    /// (or as much of it as a truncated dataset stores).
+    pub fn range(&self, prefix: u32) -> impl Iterator<Item = Row> {
+        let file = self.clone();
+        self.prefix_rows(prefix).map(move |i| {
+            let (key, count) = file.row(i);
+            // The first hex character of the key is the end of the prefix
+            Row {
+                hash: format!("{prefix:05X}{}", &encode_hex(key)[1..]),
+                count,
+            }
+        })
+    }
+
+    /// Iterates over every row with the full (or truncated) hash, in hash order.
+    pub fn rows(&self) -> impl Iterator<Item = anyhow::Result<Row>> {
+        let file = self.clone();
+        (BEGIN..=END).flat_map(move |prefix| file.range(prefix).map(Ok))
+    }
+}
+
+/// The first 8 bytes of a key as a number, for interpolating between rows.
+/// Keys with the same first 8 bytes are still told apart by the full comparison.
+fn key_value(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = key.len().min(8);
+    bytes[..len].copy_from_slice(&key[..len]);
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Context;
+
+use super::{index::PrefixIndex, split_row, HashMode, Row, Rows};
+
+/// Once the binary search narrows the range down to this many bytes, scan it.
+const SCAN_WINDOW: u64 = 64 * 1024;
+
+/// The single file format is every row of every prefix with the full hash,
+/// so the whole file is sorted by hash.
+pub fn detect_mode(path: &Path) -> anyhow::Result<HashMode> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    let (hash, _) = split_row(&line).context("The dataset's first row is invalid")?;
+    HashMode::from_hex_len(hash.len()).context("Unknown hash length in the dataset")
+}
+
+pub fn count(path: &Path, index: Option<&PrefixIndex>, hash: &str) -> anyhow::Result<Option<u32>> {
+    let Some(mut reader) = seek_to(path, index, hash)? else {
+        return Ok(None);
+    };
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let Some((row_hash, count)) = split_row(&line) else {
+            continue;
+        };
+        match row_hash.cmp(hash) {
+            std::cmp::Ordering::Less => continue,
+            std::cmp::Ordering::Equal => return Ok(Some(count)),
+            std::cmp::Ordering::Greater => return Ok(None),
+        }
+    }
+}
+
+pub fn range(path: &Path, index: Option<&PrefixIndex>, n: u32) -> anyhow::Result<Vec<Row>> {
+    let prefix = format!("{n:05X}");
+    let Some(reader) = seek_to(path, index, &prefix)? else {
+        return Ok(Vec::new());
+    };
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let Some((hash, count)) = split_row(&line) else {
+            continue;
+        };
+        if hash < prefix.as_str() {
+            continue;
+        }
+        if !hash.starts_with(&prefix) {
+            break;
+        }
+        rows.push(Row {
+            hash: hash.to_string(),
+            count,
+        });
+    }
+    Ok(rows)
+}
+
+/// Binary searches for `key` (a hash or a prefix), returning a reader positioned
+/// at or before the first row that isn't below it.
+/// With an index only the rows of the key's prefix are searched, and `None`
+/// is returned if the prefix has no rows.
+fn seek_to(
+    path: &Path,
+    index: Option<&PrefixIndex>,
+    key: &str,
+) -> anyhow::Result<Option<BufReader<File>>> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let (mut lo, mut hi) = match index {
+        Some(index) => {
+            let prefix = u32::from_str_radix(&key[..5], 16)?;
+            let range = index.range(prefix, file_len);
+            if range.is_empty() {
+                return Ok(None);
+            }
+            (range.start, range.end)
+        }
+        None => (0, file_len),
+    };
+    let mut line = String::new();
+    // Invariant: the first row not below `key` (if any) starts at or after `lo`,
+    // and the first row starting at or after `hi` is not below `key`.
+    while hi - lo > SCAN_WINDOW {
+        let mid = lo + (hi - lo) / 2;
+        let mut reader = reader_at(&file, mid)?;
+        line.clear();
+        reader.read_line(&mut line)?;
+        match split_row(&line) {
+            Some((row_hash, _)) if row_hash < key => lo = mid,
+            _ => hi = mid,
+        }
+    }
+    Ok(Some(reader_at(file, lo)?))
+}
+
+/// Returns a reader positioned at the first row starting at or after `offset`.
+fn reader_at<R: Read + Seek>(file: R, offset: u64) -> std::io::Result<BufReader<R>> {
+    let mut reader = BufReader::with_capacity(8 * 1024, file);
+    if offset == 0 {
+        reader.seek(SeekFrom::Start(0))?;
+    } else {
+        // If the byte before `offset` is a new line, the row starts exactly at `offset`
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        reader.read_until(b'\n', &mut Vec::new())?;
+    }
+    Ok(reader)
+}
+
+pub fn rows(path: &Path) -> anyhow::Result<Rows> {
+    let reader = BufReader::with_capacity(16 * 1024 * 1024, File::open(path)?);
+    Ok(Box::new(reader.lines().map(|line| {
+        let line = line?;
+        let (hash, count) = split_row(&line).with_context(|| format!("Invalid row {line:?}"))?;
+        Ok(Row {
+            hash: hash.to_string(),
+            count,
+        })
+    })))
+}
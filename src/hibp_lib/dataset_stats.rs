@@ -0,0 +1,230 @@
+use std::{collections::HashMap, io::Write, path::Path};
+
+use serde::Serialize;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    config::StatsFormat,
+    consts::LENGTH,
+    dataset::{Dataset, HashMode},
+    open_output,
+    progress_style::{get_span, leak_span, progress_style_sort, set_prefix_position},
+};
+
+#[derive(Serialize)]
+struct DatasetStats {
+    mode: HashMode,
+    total_rows: u64,
+    /// The sum of every row's count.
+    total_occurrences: u64,
+    /// Rows bucketed by count, in powers of 2.
+    histogram: Vec<Bucket>,
+    rows_per_prefix: PrefixStats,
+    /// What fraction of all occurrences the most frequent hashes make up.
+    coverage: Vec<Coverage>,
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    min_count: u32,
+    max_count: u32,
+    rows: u64,
+    occurrences: u64,
+}
+
+#[derive(Serialize)]
+struct PrefixStats {
+    min: u64,
+    max: u64,
+    mean: f64,
+    stddev: f64,
+}
+
+#[derive(Serialize)]
+struct Coverage {
+    top: u64,
+    occurrences: u64,
+    fraction: f64,
+}
+
+/// Prints the distribution of counts in a dataset (in any format) after reading it once.
+pub fn run_stats(dataset: &Path, output: Option<&Path>, format: StatsFormat) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    let span = get_span(u64::from(LENGTH), progress_style_sort()).entered();
+
+    // The distinct counts are few, so the coverage curve comes from how many rows
+    // have each count instead of sorting the rows.
+    let mut rows_with_count = HashMap::<u32, u64>::new();
+    let mut rows_per_prefix = vec![0_u64; LENGTH as usize];
+    for row in dataset.rows()? {
+        let row = row?;
+        *rows_with_count.entry(row.count).or_default() += 1;
+        if let Some(prefix) = set_prefix_position(&span, &row.hash) {
+            rows_per_prefix[prefix as usize] += 1;
+        }
+    }
+    span.pb_set_position(u64::from(LENGTH));
+    let mut counts = rows_with_count.into_iter().collect::<Vec<_>>();
+    counts.sort_unstable_by_key(|&(count, _)| std::cmp::Reverse(count));
+
+    let total_rows = counts.iter().map(|&(_, rows)| rows).sum::<u64>();
+    let total_occurrences = counts
+        .iter()
+        .map(|&(count, rows)| u64::from(count) * rows)
+        .sum::<u64>();
+    let stats = DatasetStats {
+        mode: dataset.mode(),
+        total_rows,
+        total_occurrences,
+        histogram: histogram(&counts),
+        rows_per_prefix: prefix_stats(&rows_per_prefix),
+        coverage: coverage(&counts, total_rows, total_occurrences),
+    };
+
+    let mut writer = open_output(output)?;
+    match format {
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &stats)?;
+            writeln!(writer)?;
+        }
+        StatsFormat::Text => write_text(&mut writer, &stats)?,
+    }
+    writer.flush()?;
+
+    leak_span(span);
+
+    Ok(())
+}
+
+/// Buckets are `1`, `2..=3`, `4..=7` and so on, leaving out empty ones.
+/// `counts` is `(count, rows with that count)`.
+fn histogram(counts: &[(u32, u64)]) -> Vec<Bucket> {
+    let mut buckets = (0..32)
+        .map(|bit| Bucket {
+            min_count: 1 << bit,
+            max_count: ((1_u64 << (bit + 1)) - 1) as u32,
+            rows: 0,
+            occurrences: 0,
+        })
+        .collect::<Vec<_>>();
+    let mut zero = Bucket {
+        min_count: 0,
+        max_count: 0,
+        rows: 0,
+        occurrences: 0,
+    };
+    for &(count, rows) in counts {
+        let bucket = match count.checked_ilog2() {
+            Some(bit) => &mut buckets[bit as usize],
+            None => &mut zero,
+        };
+        bucket.rows += rows;
+        bucket.occurrences += u64::from(count) * rows;
+    }
+    std::iter::once(zero)
+        .chain(buckets)
+        .filter(|bucket| bucket.rows > 0)
+        .collect()
+}
+
+fn prefix_stats(rows_per_prefix: &[u64]) -> PrefixStats {
+    let n = rows_per_prefix.len() as f64;
+    let mean = rows_per_prefix.iter().sum::<u64>() as f64 / n;
+    let variance = rows_per_prefix
+        .iter()
+        .map(|&rows| (rows as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    PrefixStats {
+        min: rows_per_prefix.iter().copied().min().unwrap_or(0),
+        max: rows_per_prefix.iter().copied().max().unwrap_or(0),
+        mean,
+        stddev: variance.sqrt(),
+    }
+}
+
+/// The occurrences covered by the top 1, 10, 100... hashes, and by all of them.
+/// `counts` is sorted by descending count.
+fn coverage(counts: &[(u32, u64)], total_rows: u64, total_occurrences: u64) -> Vec<Coverage> {
+    let fraction = |occurrences: u64| {
+        if total_occurrences == 0 {
+            0.0
+        } else {
+            occurrences as f64 / total_occurrences as f64
+        }
+    };
+    let mut points = std::iter::successors(Some(1_u64), |top| top.checked_mul(10))
+        .take_while(|&top| top < total_rows)
+        .chain(std::iter::once(total_rows))
+        .peekable();
+    let mut curve = Vec::new();
+    let (mut rows_so_far, mut occurrences_so_far) = (0, 0);
+    for &(count, rows) in counts {
+        // Points that fall within this run of equal counts
+        while let Some(&top) = points.peek() {
+            if top > rows_so_far + rows {
+                break;
+            }
+            let occurrences = occurrences_so_far + u64::from(count) * (top - rows_so_far);
+            curve.push(Coverage {
+                top,
+                occurrences,
+                fraction: fraction(occurrences),
+            });
+            points.next();
+        }
+        rows_so_far += rows;
+        occurrences_so_far += u64::from(count) * rows;
+    }
+    curve
+}
+
+fn write_text(writer: &mut dyn Write, stats: &DatasetStats) -> std::io::Result<()> {
+    writeln!(writer, "Mode: {}", stats.mode.name())?;
+    writeln!(writer, "Total rows: {}", stats.total_rows)?;
+    writeln!(writer, "Total occurrences: {}", stats.total_occurrences)?;
+    writeln!(writer)?;
+    writeln!(writer, "Rows per prefix:")?;
+    let prefixes = &stats.rows_per_prefix;
+    writeln!(
+        writer,
+        "  min {}, max {}, mean {:.2}, stddev {:.2}",
+        prefixes.min, prefixes.max, prefixes.mean, prefixes.stddev
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "Histogram of counts:")?;
+    writeln!(
+        writer,
+        "  {:>23}  {:>12}  {:>16}",
+        "count", "rows", "occurrences"
+    )?;
+    for bucket in &stats.histogram {
+        writeln!(
+            writer,
+            "  {:>23}  {:>12}  {:>16}",
+            format!("{}..={}", bucket.min_count, bucket.max_count),
+            bucket.rows,
+            bucket.occurrences
+        )?;
+    }
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "Coverage of occurrences by the most frequent hashes:"
+    )?;
+    writeln!(
+        writer,
+        "  {:>12}  {:>16}  {:>9}",
+        "top", "occurrences", "fraction"
+    )?;
+    for point in &stats.coverage {
+        writeln!(
+            writer,
+            "  {:>12}  {:>16}  {:>8.4}%",
+            point.top,
+            point.occurrences,
+            point.fraction * 100.0
+        )?;
+    }
+    Ok(())
+}
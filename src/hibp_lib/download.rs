@@ -1,79 +1,207 @@
-use std::{sync::atomic, time::Instant};
+use std::{path::Path, sync::atomic, time::Instant};
 
-use tracing::info;
+use anyhow::Context;
+use bytes::Bytes;
+use tracing::{info, warn};
 
 use super::{
-    consts::HIBP_ROOT,
-    stats::{AVG_TIME_MS, CACHE_HITS, DOWNLOADED, IN_ROUTE},
+    consts::{HIBP_ROOT, LENGTH},
+    dataset::{manifest::Manifest, HashMode},
+    stats::{ErrorKind, Stats},
     ChannelData,
 };
 
+/// The rows each prefix had in a previous download, to catch responses that
+/// still parse but have lost most of their rows (ie. truncated or throttled).
+pub struct PreviousSnapshot {
+    prefix_rows: Vec<u32>,
+    /// How far (as a fraction of the previous rows) a prefix can drop.
+    threshold: f64,
+}
+
+impl PreviousSnapshot {
+    pub fn load(manifest: &Path, mode: HashMode, threshold: f64) -> anyhow::Result<Self> {
+        let manifest = Manifest::read(manifest)
+            .with_context(|| format!("Failed to read {}", manifest.display()))?;
+        if manifest.mode != mode {
+            anyhow::bail!(
+                "The previous manifest is for {} hashes, not {}",
+                manifest.mode.name(),
+                mode.name()
+            );
+        }
+        if manifest.prefix_rows.len() != LENGTH as usize {
+            anyhow::bail!("The previous manifest has no rows per prefix to compare with");
+        }
+        Ok(Self {
+            prefix_rows: manifest.prefix_rows,
+            threshold,
+        })
+    }
+
+    /// Describes what's wrong if `rows` is far below what the prefix had before.
+    /// Prefixes that had no rows (ie. failed) before are never anomalous.
+    fn check(&self, n: u32, rows: u32) -> Option<String> {
+        let previous = self.prefix_rows[n as usize];
+        let lowest = f64::from(previous) * (1.0 - self.threshold);
+        (previous > 0 && (rows == 0 || f64::from(rows) < lowest))
+            .then(|| format!("{rows} rows, down from {previous} in the previous snapshot"))
+    }
+}
+
 pub async fn download_prefix(
     client: &reqwest::Client,
     n: u32,
     ntlm: bool,
+    add_padding: bool,
+    previous: Option<&PreviousSnapshot>,
+    stats: &Stats,
 ) -> anyhow::Result<ChannelData> {
     let n_str = format!("{n:05X}");
     let ntlm_str = if ntlm { "?mode=ntlm" } else { "" };
     let url = format!("{HIBP_ROOT}{n_str}{ntlm_str}");
 
     let mut retries = 5;
-    let mut cache_hit;
 
-    IN_ROUTE.fetch_add(1, atomic::Ordering::AcqRel);
+    stats.in_route.fetch_add(1, atomic::Ordering::AcqRel);
 
     let now = Instant::now();
-    let res_bytes = loop {
-        match client.get(&url).send().await {
-            Ok(r) => {
-                // Keep track of CloudFlare cache hits
-                cache_hit = r
-                    .headers()
-                    .get("CF-Cache-Status")
-                    .map(|v| v.as_bytes() == b"HIT")
-                    .unwrap_or(false);
-                match r.bytes().await {
-                    Ok(b) => break b,
-                    Err(e) => {
-                        if retries == 0 {
-                            return Err(e.into());
+    let (cache_hit, bytes_received, res_bytes, rows) = loop {
+        match get_body(client, &url, add_padding, stats).await {
+            Ok((cache_hit, content_length, raw)) => {
+                if let Some(problem) = incomplete_body(&raw, content_length, ntlm) {
+                    stats.record_error(ErrorKind::Truncated);
+                    if retries == 0 {
+                        stats.in_route.fetch_sub(1, atomic::Ordering::AcqRel);
+                        anyhow::bail!("Incomplete response ({problem})");
+                    }
+                    info!("Incomplete response ({problem}). Retrying 0x{n_str} {retries}/5...");
+                } else {
+                    let body = if add_padding {
+                        strip_padding(&raw)
+                    } else {
+                        raw.clone()
+                    };
+                    let rows = String::from_utf8_lossy(&body)
+                        .lines()
+                        .filter(|line| !line.is_empty())
+                        .count() as u32;
+                    match previous.and_then(|previous| previous.check(n, rows)) {
+                        None => break (cache_hit, raw.len(), body, rows),
+                        Some(problem) if retries == 0 => {
+                            // Keep it rather than lose the rows that did arrive, but flag it
+                            warn!("0x{n_str} still has {problem}");
+                            stats.record_anomalous_prefix(n);
+                            break (cache_hit, raw.len(), body, rows);
+                        }
+                        Some(problem) => {
+                            stats.record_error(ErrorKind::Anomaly);
+                            info!(
+                                "Anomalous response ({problem}). Retrying 0x{n_str} {retries}/5..."
+                            );
                         }
-                        info!(
-                            "Failed response body processing. Retrying 0x{} {}/5...",
-                            n_str, retries
-                        );
-                        retries -= 1;
-                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                     }
                 }
             }
             Err(e) => {
+                let kind = ErrorKind::of(&e);
+                stats.record_error(kind);
                 if retries == 0 {
+                    stats.in_route.fetch_sub(1, atomic::Ordering::AcqRel);
                     return Err(e.into());
                 }
-                info!("Failed request. Retrying 0x{} {}/5...", n_str, retries);
-                retries -= 1;
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                info!(
+                    "Failed request ({:?}). Retrying 0x{} {}/5...",
+                    kind, n_str, retries
+                );
             }
         }
+        stats.retries.fetch_add(1, atomic::Ordering::AcqRel);
+        retries -= 1;
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     };
-    let req_time_ms = now.elapsed().as_millis() as u64;
-    let total_downloaded = DOWNLOADED.load(atomic::Ordering::Acquire) + 1;
-    AVG_TIME_MS
-        .fetch_update(
-            atomic::Ordering::AcqRel,
-            atomic::Ordering::Acquire,
-            |prev| {
-                Some((prev * total_downloaded.saturating_sub(1) + req_time_ms) / total_downloaded)
-            },
-        )
-        .ok();
+    stats.latency.record(now.elapsed());
+    stats
+        .bytes_received
+        .fetch_add(bytes_received as u64, atomic::Ordering::AcqRel);
+    stats.set_prefix_rows(n, rows);
 
-    IN_ROUTE.fetch_sub(1, atomic::Ordering::AcqRel);
-    DOWNLOADED.fetch_add(1, atomic::Ordering::AcqRel);
+    stats.in_route.fetch_sub(1, atomic::Ordering::AcqRel);
+    stats.downloaded.fetch_add(1, atomic::Ordering::AcqRel);
     if cache_hit {
-        CACHE_HITS.fetch_add(1, atomic::Ordering::AcqRel);
+        stats.cache_hits.fetch_add(1, atomic::Ordering::AcqRel);
     }
 
     Ok((n, res_bytes))
 }
+
+/// Performs a single request, returning whether it was a Cloudflare cache hit
+/// along with the `Content-Length` (if any) and the response body.
+async fn get_body(
+    client: &reqwest::Client,
+    url: &str,
+    add_padding: bool,
+    stats: &Stats,
+) -> Result<(bool, Option<u64>, Bytes), reqwest::Error> {
+    let mut request = client.get(url);
+    if add_padding {
+        request = request.header("Add-Padding", "true");
+    }
+    let r = request.send().await?;
+    stats.record_status(r.status().as_u16());
+    let r = r.error_for_status()?;
+    // Keep track of CloudFlare cache hits
+    let cache_hit = r
+        .headers()
+        .get("CF-Cache-Status")
+        .map(|v| v.as_bytes() == b"HIT")
+        .unwrap_or(false);
+    let content_length = r.content_length();
+    Ok((cache_hit, content_length, r.bytes().await?))
+}
+
+/// Describes what's wrong if the body was cut short, ie. by a reset HTTP/2 stream.
+/// It has to match `Content-Length` when there is one, have rows, and its last line
+/// has to be a whole `SUFFIX:COUNT` row (responses don't end with a line break).
+fn incomplete_body(body: &[u8], content_length: Option<u64>, ntlm: bool) -> Option<String> {
+    if let Some(expected) = content_length {
+        if body.len() as u64 != expected {
+            return Some(format!("received {} of {expected} bytes", body.len()));
+        }
+    }
+    let body = body.strip_suffix(b"\r\n").unwrap_or(body);
+    // Every prefix has rows, so an empty body never came through whole
+    if body.is_empty() {
+        return Some("the body is empty".to_string());
+    }
+    let last_line = body.rsplit(|&b| b == b'\n').next().unwrap_or(body);
+    let last_line = last_line.strip_suffix(b"\r").unwrap_or(last_line);
+    let suffix_len = if ntlm { 27 } else { 35 };
+    let is_row = match last_line.iter().position(|&b| b == b':') {
+        Some(colon) => {
+            let (suffix, count) = (&last_line[..colon], &last_line[colon + 1..]);
+            suffix.len() == suffix_len
+                && suffix.iter().all(u8::is_ascii_hexdigit)
+                && !count.is_empty()
+                && count.iter().all(u8::is_ascii_digit)
+        }
+        None => false,
+    };
+    (!is_row).then(|| {
+        format!(
+            "the last line is not a whole row: {:?}",
+            String::from_utf8_lossy(last_line)
+        )
+    })
+}
+
+/// Removes the fake `:0` rows that `Add-Padding` responses are padded with.
+/// The rows are kept in the same `\r\n` separated format as the response.
+fn strip_padding(body: &[u8]) -> Bytes {
+    String::from_utf8_lossy(body)
+        .lines()
+        .filter(|line| !line.trim_end().ends_with(":0"))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        .into()
+}
@@ -0,0 +1,93 @@
+use std::{
+    fmt,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use serde::Serialize;
+
+/// The exit code of the process, so wrapper scripts can react without parsing stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitStatus {
+    Success = 0,
+    /// Any error not covered by the other variants.
+    Failure = 1,
+    /// The command finished, but some prefixes could not be downloaded.
+    PartialSuccess = 3,
+    /// The input or downloaded data failed a sanity check.
+    ValidationFailure = 4,
+    /// Reading or writing a file failed.
+    IoError = 5,
+    /// Ctrl-C was pressed before the command finished.
+    Interrupted = 130,
+}
+
+impl ExitStatus {
+    pub fn of(result: &anyhow::Result<()>) -> Self {
+        let Err(e) = result else {
+            return ExitStatus::Success;
+        };
+        if let Some(failure) = e.chain().find_map(|c| c.downcast_ref::<Failure>()) {
+            failure.status
+        } else if e.chain().any(|c| c.is::<std::io::Error>()) {
+            ExitStatus::IoError
+        } else {
+            ExitStatus::Failure
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+/// An error that should end the process with a specific `ExitStatus`.
+#[derive(Debug)]
+pub struct Failure {
+    pub status: ExitStatus,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(status: ExitStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Returns a flag that gets set once Ctrl-C is pressed.
+/// For blocking code that can't await `tokio::signal::ctrl_c` directly.
+pub fn interrupt_flag() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let setter = Arc::clone(&flag);
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        if runtime.block_on(tokio::signal::ctrl_c()).is_ok() {
+            setter.store(true, Ordering::Release);
+        }
+    });
+    flag
+}
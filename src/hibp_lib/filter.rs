@@ -0,0 +1,107 @@
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use tracing::{info, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    consts::LENGTH,
+    dataset::{bloom::BloomFilter, parse_hash, Dataset},
+    progress_style::{get_span, leak_span, progress_style_sort, set_prefix_position},
+};
+
+/// Builds a Bloom filter of the hashes in a dataset seen at least `min_count` times.
+///
+/// The filter has to be sized before anything is added, so unless the manifest
+/// already has the row count, the dataset is read twice: once to count the rows
+/// that will be added, and once to add them.
+pub fn run_filter_build(
+    dataset: &Path,
+    output_file: &Path,
+    fp_rate: f64,
+    min_count: u32,
+) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    dataset.ensure_not_truncated()?;
+    let span = get_span(u64::from(LENGTH), progress_style_sort()).entered();
+
+    let capacity = match dataset.manifest() {
+        Some(manifest) if min_count <= 1 => manifest.rows,
+        _ => {
+            let mut capacity = 0;
+            for row in dataset.rows()? {
+                let row = row?;
+                if row.count >= min_count {
+                    capacity += 1;
+                }
+                set_prefix_position(&span, &row.hash);
+            }
+            capacity
+        }
+    };
+    let mut filter = BloomFilter::new(dataset.mode(), capacity, fp_rate, min_count)?;
+    span.pb_set_position(0);
+    for row in dataset.rows()? {
+        let row = row?;
+        if row.count >= min_count {
+            filter.insert(&row.hash)?;
+        }
+        set_prefix_position(&span, &row.hash);
+    }
+    filter.write(output_file)?;
+    span.pb_set_position(u64::from(LENGTH));
+    info!(
+        "Added {} {} hashes to a {} byte filter (expected false positive rate {:.6}%)",
+        filter.len(),
+        filter.mode().name(),
+        filter.size_bytes(),
+        filter.false_positive_rate() * 100.0
+    );
+
+    leak_span(span);
+
+    Ok(())
+}
+
+/// Prints whether `hash` is in the filter ("found", which may be a false positive)
+/// or not ("not found", which is certain).
+/// Without a hash (or with "-") every line of stdin is checked and printed as `HASH:found`.
+pub fn run_filter_check(hash: Option<&str>, filter: &Path) -> anyhow::Result<()> {
+    let filter = BloomFilter::open(filter)?;
+    let found = |found: bool| if found { "found" } else { "not found" };
+    match hash {
+        Some(hash) if hash != "-" => {
+            let (_, hash) = parse_hash(hash)?;
+            println!("{}", found(filter.contains(&hash)?));
+        }
+        _ => {
+            let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+            for line in std::io::stdin().lock().lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let hash = match parse_hash(&line) {
+                    Ok((mode, hash)) if mode == filter.mode() => hash,
+                    Ok(_) => {
+                        warn!(
+                            "Skipping {} hash in a {} filter",
+                            line.trim(),
+                            filter.mode().name()
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Skipping line: {e}");
+                        continue;
+                    }
+                };
+                writeln!(stdout, "{hash}:{}", found(filter.contains(&hash)?))?;
+            }
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::{
+    sync::{atomic, mpsc, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::stats::Stats;
+
+/// Prints one line of JSON to stdout every interval until finished.
+/// This is the `--progress json` replacement for the progress bar.
+pub struct JsonProgress {
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Serialize)]
+struct ProgressLine {
+    span: &'static str,
+    position: u64,
+    length: u64,
+    percent: f64,
+    elapsed_secs: f64,
+    /// Position increments per second since the start.
+    rate: f64,
+    eta_secs: Option<f64>,
+    cache_hits: u64,
+    retries: u64,
+    errors: u64,
+}
+
+impl JsonProgress {
+    /// `position` reads the current position out of the stats.
+    pub fn spawn(
+        span: &'static str,
+        length: u64,
+        interval: Duration,
+        stats: Arc<Stats>,
+        position: fn(&Stats) -> u64,
+    ) -> Self {
+        let (stop, rx) = mpsc::channel();
+        let start = Instant::now();
+        let handle = std::thread::spawn(move || loop {
+            let done = rx.recv_timeout(interval) != Err(mpsc::RecvTimeoutError::Timeout);
+            let line = ProgressLine::new(span, position(&stats), length, start.elapsed(), &stats);
+            println!("{}", serde_json::to_string(&line).unwrap());
+            if done {
+                break;
+            }
+        });
+        Self { stop, handle }
+    }
+
+    /// Prints the final line and waits for the thread to exit.
+    pub fn finish(self) {
+        self.stop.send(()).ok();
+        self.handle.join().ok();
+    }
+}
+
+impl ProgressLine {
+    fn new(
+        span: &'static str,
+        position: u64,
+        length: u64,
+        elapsed: Duration,
+        stats: &Stats,
+    ) -> Self {
+        let elapsed_secs = elapsed.as_secs_f64();
+        let rate = if elapsed_secs > 0.0 {
+            position as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        let eta_secs = (rate > 0.0).then(|| length.saturating_sub(position) as f64 / rate);
+        let percent = if length == 0 {
+            0.0
+        } else {
+            position as f64 * 100.0 / length as f64
+        };
+        Self {
+            span,
+            position,
+            length,
+            percent,
+            elapsed_secs,
+            rate,
+            eta_secs,
+            cache_hits: stats.cache_hits.load(atomic::Ordering::Acquire),
+            retries: stats.retries.load(atomic::Ordering::Acquire),
+            errors: stats.total_errors(),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use tracing::warn;
+
+use super::dataset::{parse_hash, Dataset};
+
+/// Prints how many times `hash` was seen, or "not found".
+/// Without a hash (or with "-") every line of stdin is looked up and printed as `HASH:COUNT`.
+pub fn run_lookup(hash: Option<&str>, dataset: &Path) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    match hash {
+        Some(hash) if hash != "-" => {
+            let (_, hash) = parse_hash(hash)?;
+            match dataset.count(&hash)? {
+                Some(count) => println!("{count}"),
+                None => println!("not found"),
+            }
+        }
+        _ => {
+            let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+            for line in std::io::stdin().lock().lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let hash = match parse_hash(&line) {
+                    Ok((mode, hash)) if mode == dataset.mode() => hash,
+                    Ok(_) => {
+                        warn!(
+                            "Skipping {} hash in a {} dataset",
+                            line.trim(),
+                            dataset.mode().name()
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Skipping line: {e}");
+                        continue;
+                    }
+                };
+                match dataset.count(&hash)? {
+                    Some(count) => writeln!(stdout, "{hash}:{count}")?,
+                    None => writeln!(stdout, "{hash}:not found")?,
+                }
+            }
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{atomic, Arc},
+    thread::JoinHandle,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, StatusCode,
+};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use super::{
+    config::Config,
+    consts::LENGTH,
+    stats::{ErrorKind, Stats},
+};
+
+/// How often the `--metrics-textfile` is rewritten.
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the request duration histogram buckets.
+const LATENCY_BOUNDS: [Duration; 11] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Exports the run's `Stats` in the Prometheus text format, either over HTTP
+/// (`--metrics-listen`) or as a node_exporter textfile (`--metrics-textfile`).
+///
+/// Everything runs on its own thread so that it works for both the async
+/// download and the blocking sort.
+pub struct MetricsExporter {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsExporter {
+    /// Returns `None` if no metrics options were given.
+    pub fn start(config: &Config, stats: &Arc<Stats>) -> anyhow::Result<Option<Self>> {
+        if config.metrics_listen.is_none() && config.metrics_textfile.is_none() {
+            return Ok(None);
+        }
+        // Bind here so that a bad address is reported before any work starts.
+        let listener = config
+            .metrics_listen
+            .map(|addr| {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("Failed to bind metrics listener on {addr}"))?;
+                listener.set_nonblocking(true)?;
+                anyhow::Ok(listener)
+            })
+            .transpose()?;
+        let textfile = config.metrics_textfile.clone();
+        let stats = Arc::clone(stats);
+        let (stop, stop_rx) = oneshot::channel();
+        let handle = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(export(listener, textfile, stats, stop_rx));
+        });
+        Ok(Some(Self { stop, handle }))
+    }
+
+    /// Writes the textfile one last time and shuts down the HTTP server.
+    pub fn finish(self) {
+        self.stop.send(()).ok();
+        self.handle.join().ok();
+    }
+}
+
+async fn export(
+    listener: Option<TcpListener>,
+    textfile: Option<PathBuf>,
+    stats: Arc<Stats>,
+    mut stop_rx: oneshot::Receiver<()>,
+) {
+    if let Some(listener) = listener {
+        let stats = Arc::clone(&stats);
+        let make_service = make_service_fn(move |_| {
+            let stats = Arc::clone(&stats);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = if req.uri().path() == "/metrics" {
+                        Response::builder()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .body(Body::from(render(&stats)))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                    };
+                    async move { response }
+                }))
+            }
+        });
+        match hyper::Server::from_tcp(listener) {
+            Ok(server) => {
+                tokio::spawn(async move {
+                    if let Err(e) = server.serve(make_service).await {
+                        warn!("Metrics server failed: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Metrics server failed to start: {e}"),
+        }
+    }
+
+    loop {
+        let stopped = tokio::time::timeout(TEXTFILE_INTERVAL, &mut stop_rx)
+            .await
+            .is_ok();
+        if let Some(path) = &textfile {
+            if let Err(e) = write_textfile(path, &stats).await {
+                warn!("Failed to write metrics to {}: {e}", path.display());
+            }
+        }
+        if stopped {
+            break;
+        }
+    }
+}
+
+/// Writes to a temporary file first so node_exporter never reads a partial file.
+async fn write_textfile(path: &Path, stats: &Stats) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, render(stats)).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn render(stats: &Stats) -> String {
+    let load = |counter: &atomic::AtomicU64| counter.load(atomic::Ordering::Acquire);
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        writeln!(out, "# HELP hibp_downloader_{name} {help}").unwrap();
+        writeln!(out, "# TYPE hibp_downloader_{name} {kind}").unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "hibp_downloader_{name}{labels} {value}").unwrap();
+        }
+    };
+    let single = |value: u64| [(String::new(), value)];
+
+    metric(
+        "start_time_seconds",
+        "gauge",
+        "Unix time when this run started.",
+        &single(
+            stats
+                .started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        ),
+    );
+    metric(
+        "prefixes_expected",
+        "gauge",
+        "The number of prefixes that make up a full download.",
+        &single(u64::from(LENGTH)),
+    );
+    metric(
+        "prefixes_downloaded_total",
+        "counter",
+        "Prefixes downloaded.",
+        &single(load(&stats.downloaded)),
+    );
+    metric(
+        "prefixes_written_total",
+        "counter",
+        "Prefixes written to the output.",
+        &single(load(&stats.written_to_file)),
+    );
+    metric(
+        "requests_in_flight",
+        "gauge",
+        "Prefixes currently being requested.",
+        &single(load(&stats.in_route)),
+    );
+    metric(
+        "reorder_buffer_prefixes",
+        "gauge",
+        "Prefixes downloaded but waiting in the writer's reorder buffer.",
+        &single(load(&stats.buffered)),
+    );
+    metric(
+        "cache_hits_total",
+        "counter",
+        "Responses served from the Cloudflare cache.",
+        &single(load(&stats.cache_hits)),
+    );
+    metric(
+        "retries_total",
+        "counter",
+        "Requests that were retried.",
+        &single(load(&stats.retries)),
+    );
+    metric(
+        "errors_total",
+        "counter",
+        "Failed requests by class.",
+        &ErrorKind::ALL.map(|kind| {
+            (
+                format!("{{class=\"{}\"}}", kind.as_str()),
+                stats.errors(kind),
+            )
+        }),
+    );
+    metric(
+        "responses_total",
+        "counter",
+        "Responses by HTTP status code.",
+        &stats
+            .status_codes()
+            .into_iter()
+            .map(|(code, count)| (format!("{{code=\"{code}\"}}"), count))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "received_bytes_total",
+        "counter",
+        "Response body bytes received.",
+        &single(load(&stats.bytes_received)),
+    );
+    metric(
+        "received_rows_total",
+        "counter",
+        "Hash rows received.",
+        &single(load(&stats.rows_received)),
+    );
+    metric(
+        "written_rows_total",
+        "counter",
+        "Hash rows written to the output.",
+        &single(load(&stats.rows_written)),
+    );
+    metric(
+        "sort_progress_total",
+        "counter",
+        "Rows read plus rows written by the sort.",
+        &single(load(&stats.sort_progress)),
+    );
+    metric(
+        "sort_length",
+        "gauge",
+        "Estimated final value of sort_progress_total.",
+        &single(load(&stats.sort_length)),
+    );
+
+    let name = "hibp_downloader_request_duration_seconds";
+    writeln!(
+        out,
+        "# HELP {name} Time taken per prefix, including retries."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+    let counts = stats.latency.cumulative_counts(&LATENCY_BOUNDS);
+    for (bound, count) in LATENCY_BOUNDS.iter().zip(counts) {
+        let le = bound.as_secs_f64();
+        writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}").unwrap();
+    }
+    let total = stats.latency.count();
+    writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {total}").unwrap();
+    writeln!(out, "{name}_sum {}", stats.latency.sum().as_secs_f64()).unwrap();
+    writeln!(out, "{name}_count {total}").unwrap();
+
+    out
+}
@@ -1,79 +1,286 @@
+mod audit;
 mod buffered_string_writer;
+mod build_index;
+mod bulk_check;
+mod check_online;
+mod check_password;
 pub mod config;
 mod consts;
+pub mod dataset;
+mod dataset_stats;
 mod download;
+pub mod exit_status;
+mod filter;
+mod json_progress;
+mod lookup;
+mod metrics;
+mod pack;
 mod progress_style;
+mod serve;
 mod sort;
 mod stats;
+mod summary;
 mod tasks;
+mod top;
+mod truncate;
 
-use std::sync::atomic;
+use std::{
+    io::Write,
+    path::Path,
+    sync::{atomic, Arc},
+    time::{Duration, SystemTime},
+};
 
 use bytes::Bytes;
-use config::Config;
+use config::{Config, OutputFormat, ProgressMode};
 use consts::{LENGTH, USER_AGENT};
+use dataset::{
+    manifest::{unix_secs, Manifest},
+    Format, HashMode,
+};
+use download::PreviousSnapshot;
+use exit_status::{ExitStatus, Failure};
+use json_progress::JsonProgress;
+use metrics::MetricsExporter;
 use progress_style::{get_span, progress_style_download};
 use reqwest::Client;
+use stats::Stats;
+use summary::Summary;
 use tasks::{download_task, progress_task, writer_task};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tracing::Instrument;
 use tracing_indicatif::{span_ext::IndicatifSpanExt, IndicatifLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, FmtSubscriber};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, EnvFilter, FmtSubscriber};
 
-pub fn init_logging_and_progress() {
-    let indicatif_layer = IndicatifLayer::new();
-    tracing::subscriber::set_global_default(
-        FmtSubscriber::builder()
-            .with_env_filter(EnvFilter::from_default_env())
-            .with_writer(indicatif_layer.get_stderr_writer())
-            .finish()
-            .with(indicatif_layer),
-    )
+pub fn init_logging_and_progress(mode: ProgressMode) {
+    match mode {
+        ProgressMode::Bar => {
+            let indicatif_layer = IndicatifLayer::new();
+            tracing::subscriber::set_global_default(
+                FmtSubscriber::builder()
+                    .with_env_filter(EnvFilter::from_default_env())
+                    .with_writer(indicatif_layer.get_stderr_writer())
+                    .finish()
+                    .with(indicatif_layer),
+            )
+        }
+        // Without the indicatif layer all the progress bar span methods are no-ops.
+        ProgressMode::Json | ProgressMode::None => tracing::subscriber::set_global_default(
+            FmtSubscriber::builder()
+                .with_env_filter(
+                    EnvFilter::builder()
+                        .with_default_directive(LevelFilter::WARN.into())
+                        .from_env_lossy(),
+                )
+                .with_writer(std::io::stderr)
+                .finish(),
+        ),
+    }
     .expect("Logging subscriber failed");
     LogTracer::init().unwrap();
 }
 
+/// Starts the JSON progress reporter if `--progress json` was chosen.
+fn start_json_progress(
+    config: &Config,
+    span: &'static str,
+    length: u64,
+    stats: &Arc<Stats>,
+    position: fn(&Stats) -> u64,
+) -> Option<JsonProgress> {
+    (config.progress == ProgressMode::Json).then(|| {
+        JsonProgress::spawn(
+            span,
+            length,
+            Duration::from_secs(config.progress_interval),
+            Arc::clone(stats),
+            position,
+        )
+    })
+}
+
+/// Opens the file a report or result is written to, or stdout if there isn't one.
+fn open_output(output: Option<&Path>) -> std::io::Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+    })
+}
+
 pub type ChannelData = (u32, Bytes);
-pub fn init_client_channels(
-    concurrent_requests: usize,
-) -> (Client, Sender<ChannelData>, Receiver<ChannelData>) {
-    let client = reqwest::Client::builder()
+pub fn build_client() -> Client {
+    reqwest::Client::builder()
         .user_agent(USER_AGENT)
         .timeout(std::time::Duration::from_secs(5))
         .build()
-        .unwrap();
+        .unwrap()
+}
+
+pub fn init_client_channels(
+    concurrent_requests: usize,
+) -> (Client, Sender<ChannelData>, Receiver<ChannelData>) {
+    let client = build_client();
     let (tx, rx) = tokio::sync::mpsc::channel::<ChannelData>(concurrent_requests);
 
     (client, tx, rx)
 }
 
+pub use audit::run_audit;
+pub use build_index::run_index;
+pub use bulk_check::run_bulk_check;
+pub use check_online::run_check_online;
+pub use check_password::run_check_password;
+pub use dataset_stats::run_stats;
+pub use filter::{run_filter_build, run_filter_check};
+pub use lookup::run_lookup;
+pub use pack::run_pack;
+pub use serve::{run_serve, CheckLimits};
 pub use sort::run_sort;
+pub use top::run_top;
+pub use truncate::run_truncate;
+/// Records what was downloaded next to the output, for the consumers of the dataset.
+fn write_manifest(config: &Config, stats: &Stats) -> anyhow::Result<()> {
+    let format = if std::fs::metadata(&config.output_path)?.is_dir() {
+        Format::Directory
+    } else {
+        match config.format {
+            OutputFormat::Text => Format::Text,
+            OutputFormat::Packed => Format::Packed,
+        }
+    };
+    Manifest {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        mode: if config.ntlm {
+            HashMode::Ntlm
+        } else {
+            HashMode::Sha1
+        },
+        format,
+        download_started: unix_secs(stats.started),
+        download_finished: unix_secs(SystemTime::now()),
+        rows: stats.rows_written.load(atomic::Ordering::Acquire),
+        min_count: (config.min_count > 1).then_some(config.min_count),
+        rows_dropped: stats.rows_dropped.load(atomic::Ordering::Acquire),
+        failed_prefixes: stats
+            .failed_prefixes()
+            .into_iter()
+            .map(|n| format!("{n:05X}"))
+            .collect(),
+        anomalous_prefixes: stats
+            .anomalous_prefixes()
+            .into_iter()
+            .map(|n| format!("{n:05X}"))
+            .collect(),
+        prefix_rows: stats.all_prefix_rows(),
+        checksum: stats.output_checksum.get().cloned(),
+    }
+    .write(&config.output_path)
+}
+
 pub fn run_download(config: &Config) -> anyhow::Result<()> {
-    let body = async move {
+    let stats = Arc::new(Stats::new());
+    let mode = if config.ntlm {
+        HashMode::Ntlm
+    } else {
+        HashMode::Sha1
+    };
+    let body = async {
+        let previous = match &config.previous_manifest {
+            Some(path) => Some(Arc::new(PreviousSnapshot::load(
+                path,
+                mode,
+                config.anomaly_threshold,
+            )?)),
+            None => None,
+        };
         let concurrent_requests = config.workers * config.multiplier;
-        let span = get_span(u64::from(LENGTH), progress_style_download());
+        let span = get_span(
+            u64::from(LENGTH),
+            progress_style_download(Arc::clone(&stats)),
+        );
         let enter = span.enter();
         let (client, tx, rx) = init_client_channels(concurrent_requests);
-        let progress_task = tokio::spawn(progress_task().instrument(span.clone()));
-        let file =
-            buffered_string_writer::BufferedStringWriter::from_file(&config.output_path).await?;
+        let metrics = MetricsExporter::start(config, &stats)?;
+        let progress_task =
+            tokio::spawn(progress_task(Arc::clone(&stats)).instrument(span.clone()));
+        let json_progress =
+            start_json_progress(config, "download", u64::from(LENGTH), &stats, |s| {
+                s.downloaded.load(atomic::Ordering::Acquire)
+            });
+        let file = buffered_string_writer::BufferedStringWriter::from_file(
+            &config.output_path,
+            config.format,
+            mode,
+            config.min_count,
+            Arc::clone(&stats),
+        )
+        .await?;
         let writer_task = tokio::spawn(writer_task(rx, file));
-        let download_task =
-            tokio::spawn(download_task(client, concurrent_requests, tx, config.ntlm));
+        let mut download_task = tokio::spawn(download_task(
+            client,
+            concurrent_requests,
+            tx,
+            config.ntlm,
+            config.add_padding,
+            previous,
+            Arc::clone(&stats),
+        ));
 
-        download_task.await??;
+        let interrupted = tokio::select! {
+            res = &mut download_task => {
+                res??;
+                false
+            }
+            _ = tokio::signal::ctrl_c() => {
+                // Dropping the in-flight requests closes the channel,
+                // so the writer still writes out everything it has.
+                download_task.abort();
+                true
+            }
+        };
         writer_task.await??;
         progress_task.abort();
+        if let Some(json_progress) = json_progress {
+            json_progress.finish();
+        }
+        if let Some(metrics) = metrics {
+            metrics.finish();
+        }
 
         // Leak the span so that it never gets cleaned up
         // (We want it to remain after the program finishes so the logs aren't deleted)
         // Give it a chance to write to stderr (since it can't flush in the Drop impl)
-        span.pb_set_position(stats::DOWNLOADED.load(atomic::Ordering::Acquire));
+        span.pb_set_position(stats.downloaded.load(atomic::Ordering::Acquire));
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
         core::mem::forget(enter);
         core::mem::forget(span);
 
+        if interrupted {
+            return Err(Failure::new(ExitStatus::Interrupted, "Interrupted").into());
+        }
+        write_manifest(config, &stats)?;
+        let failed = stats.failed_prefixes().len();
+        if failed > 0 {
+            // Nothing was downloaded, so there is no partial success to speak of
+            let status = if failed == LENGTH as usize {
+                ExitStatus::Failure
+            } else {
+                ExitStatus::PartialSuccess
+            };
+            return Err(
+                Failure::new(status, format!("{failed} prefixes failed to download")).into(),
+            );
+        }
+        let anomalous = stats.anomalous_prefixes().len();
+        if anomalous > 0 {
+            return Err(Failure::new(
+                ExitStatus::ValidationFailure,
+                format!("{anomalous} prefixes have far fewer rows than in the previous manifest"),
+            )
+            .into());
+        }
+
         anyhow::Ok(())
     };
 
@@ -85,7 +292,8 @@ pub fn run_download(config: &Config) -> anyhow::Result<()> {
         .worker_threads(config.workers)
         .build()
         .unwrap();
-    runtime.block_on(body)?;
+    let result = runtime.block_on(body);
     core::mem::forget(runtime);
-    Ok(())
+    Summary::download(&stats, &config.output_path, &result).emit(config.summary_file.as_deref())?;
+    result
 }
@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    consts::LENGTH,
+    dataset::{
+        packed::{full_key_len, PackedWriter},
+        Dataset,
+    },
+    progress_style::{get_span, leak_span, progress_style_sort, set_prefix_position},
+};
+
+/// Converts a text or directory dataset into the packed binary format,
+/// leaving out rows seen fewer than `min_count` times.
+pub fn run_pack(dataset: &Path, output_file: &Path, min_count: u32) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    dataset.ensure_not_truncated()?;
+    let mode = dataset.mode();
+    let span = get_span(u64::from(LENGTH), progress_style_sort()).entered();
+
+    let mut writer = PackedWriter::create(output_file, mode, full_key_len(mode))?;
+    let (mut rows, mut rows_dropped) = (0, 0);
+    for row in dataset.rows()? {
+        let row = row?;
+        if row.count < min_count {
+            rows_dropped += 1;
+            continue;
+        }
+        rows += 1;
+        writer.write_row(&row.hash, row.count)?;
+        set_prefix_position(&span, &row.hash);
+    }
+    writer.finish()?;
+    span.pb_set_position(u64::from(LENGTH));
+    // Carry over where the data came from
+    if let Some(manifest) = dataset.manifest() {
+        manifest
+            .packed(rows, min_count, rows_dropped)
+            .write(output_file)?;
+    }
+
+    leak_span(span);
+
+    Ok(())
+}
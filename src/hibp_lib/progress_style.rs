@@ -1,10 +1,10 @@
-use std::sync::atomic;
+use std::sync::{atomic, Arc};
 
 use indicatif::{ProgressState, ProgressStyle};
-use tracing::{error_span, Span};
+use tracing::{error_span, span::EnteredSpan, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-use super::stats::{AVG_TIME_MS, CACHE_HITS, DOWNLOADED, IN_ROUTE};
+use super::stats::Stats;
 
 pub fn get_span(length: u64, style: ProgressStyle) -> Span {
     // Use error so the progress bar is always shown
@@ -14,6 +14,22 @@ pub fn get_span(length: u64, style: ProgressStyle) -> Span {
     span
 }
 
+/// Leaks the entered span so that it never gets cleaned up.
+/// (We want it to remain after the program finishes so the logs aren't deleted)
+/// It gets a chance to write to stderr first, since it can't flush in the Drop impl.
+pub fn leak_span(span: EnteredSpan) {
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    core::mem::forget(span);
+}
+
+/// Moves a progress bar over the 5 character prefixes to the prefix of `hash`,
+/// and returns that prefix. Hashes that don't start with one are skipped.
+pub fn set_prefix_position(span: &Span, hash: &str) -> Option<u32> {
+    let prefix = u32::from_str_radix(hash.get(..5)?, 16).ok()?;
+    span.pb_set_position(u64::from(prefix));
+    Some(prefix)
+}
+
 /// This function gives a percentage up to 6 decimal places.
 fn get_pct(numerator: u64, denominator: u64) -> f64 {
     if denominator == 0 {
@@ -23,10 +39,10 @@ fn get_pct(numerator: u64, denominator: u64) -> f64 {
 }
 
 /// For use with `indicatif::style::ProgressStyle::with_key`
-fn cache_stats_tracker(_: &ProgressState, w: &mut dyn std::fmt::Write) {
-    let cache = CACHE_HITS.load(atomic::Ordering::Acquire);
-    let total = DOWNLOADED.load(atomic::Ordering::Acquire);
-    let in_flight = IN_ROUTE.load(atomic::Ordering::Acquire);
+fn cache_stats_tracker(stats: &Stats, _: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let cache = stats.cache_hits.load(atomic::Ordering::Acquire);
+    let total = stats.downloaded.load(atomic::Ordering::Acquire);
+    let in_flight = stats.in_route.load(atomic::Ordering::Acquire);
     let pct = get_pct(cache, total);
     w.write_fmt(format_args!(
         "{cache}/{total} {pct}% (In flight requests: {in_flight})"
@@ -35,13 +51,62 @@ fn cache_stats_tracker(_: &ProgressState, w: &mut dyn std::fmt::Write) {
 }
 
 /// For use with `indicatif::style::ProgressStyle::with_key`
-fn avg_request_ms_tracker(_: &ProgressState, w: &mut dyn std::fmt::Write) {
-    let avg_time = AVG_TIME_MS.load(atomic::Ordering::Acquire);
-    w.write_fmt(format_args!("{avg_time}")).unwrap();
+fn latency_tracker(stats: &Stats, _: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let [p50, p95, p99] = [0.50, 0.95, 0.99].map(|q| stats.latency.quantile(q).as_millis());
+    w.write_fmt(format_args!("{p50}/{p95}/{p99}")).unwrap();
 }
 
-pub fn progress_style_download() -> ProgressStyle {
-    ProgressStyle::with_template(
+/// For use with `indicatif::style::ProgressStyle::with_key`
+fn writer_stats_tracker(stats: &Stats, _: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let written = stats.written_to_file.load(atomic::Ordering::Acquire);
+    let downloaded = stats.downloaded.load(atomic::Ordering::Acquire);
+    let buffered = stats.buffered.load(atomic::Ordering::Acquire);
+    w.write_fmt(format_args!(
+        "{written}/{downloaded} (Reorder buffer: {buffered})"
+    ))
+    .unwrap();
+}
+
+/// For use with `indicatif::style::ProgressStyle::with_key`
+fn throughput_tracker(stats: &Stats, state: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let secs = state.elapsed().as_secs_f64();
+    let (mb_per_sec, rows_per_sec) = if secs > 0.0 {
+        let bytes = stats.bytes_received.load(atomic::Ordering::Acquire) as f64;
+        let rows = stats.rows_written.load(atomic::Ordering::Acquire) as f64;
+        (bytes / 1_000_000.0 / secs, rows / secs)
+    } else {
+        (0.0, 0.0)
+    };
+    w.write_fmt(format_args!(
+        "{mb_per_sec:.2} MB/s received, {rows_per_sec:.0} rows/s written"
+    ))
+    .unwrap();
+}
+
+/// For use with `indicatif::style::ProgressStyle::with_key`
+fn retry_stats_tracker(stats: &Stats, _: &ProgressState, w: &mut dyn std::fmt::Write) {
+    let retries = stats.retries.load(atomic::Ordering::Acquire);
+    let errors = stats.total_errors();
+    w.write_fmt(format_args!("{retries} retries, {errors} errors"))
+        .unwrap();
+}
+
+/// Registers a tracker that reads from the shared `Stats`.
+fn with_stats_key(
+    style: ProgressStyle,
+    key: &'static str,
+    stats: &Arc<Stats>,
+    tracker: fn(&Stats, &ProgressState, &mut dyn std::fmt::Write),
+) -> ProgressStyle {
+    let stats = Arc::clone(stats);
+    style.with_key(
+        key,
+        move |state: &ProgressState, w: &mut dyn std::fmt::Write| tracker(&stats, state, w),
+    )
+}
+
+pub fn progress_style_download(stats: Arc<Stats>) -> ProgressStyle {
+    let style = ProgressStyle::with_template(
         "\
         {spinner:.green} \
         [{elapsed_precise}] \
@@ -49,14 +114,20 @@ pub fn progress_style_download() -> ProgressStyle {
         [{percent}%] \
         [{wide_bar:.pink/blue}]\n\
         Request speed: {per_sec}\n\
-        Avg Request time: {avg_request_ms} ms\n\
+        Request time p50/p95/p99: {latency} ms\n\
         Current: {human_pos}/{human_len}\n\
+        Written to output: {writer_stats}\n\
+        Throughput: {throughput}\n\
+        Errors: {retry_stats}\n\
         Cloudflare cache hits: {cache_stats}",
     )
     .unwrap()
-    .with_key("cache_stats", cache_stats_tracker)
-    .with_key("avg_request_ms", avg_request_ms_tracker)
-    .progress_chars("#>-")
+    .progress_chars("#>-");
+    let style = with_stats_key(style, "cache_stats", &stats, cache_stats_tracker);
+    let style = with_stats_key(style, "latency", &stats, latency_tracker);
+    let style = with_stats_key(style, "writer_stats", &stats, writer_stats_tracker);
+    let style = with_stats_key(style, "throughput", &stats, throughput_tracker);
+    with_stats_key(style, "retry_stats", &stats, retry_stats_tracker)
 }
 
 pub fn progress_style_sort() -> ProgressStyle {
@@ -0,0 +1,380 @@
+use std::{collections::HashSet, convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use hyper::{
+    body::HttpBody,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::dataset::{manifest::Manifest, parse_hash, Dataset, Format, HashMode};
+
+/// The body upstream answers with when the prefix isn't 5 hex characters.
+const INVALID_PREFIX: &str = "The hash prefix was not in a valid format";
+
+/// How many rows a padded response has.
+const PADDED_ROWS: std::ops::RangeInclusive<usize> = 800..=1000;
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// The datasets being served, at most one per hash mode.
+struct Datasets {
+    sha1: Option<Dataset>,
+    ntlm: Option<Dataset>,
+}
+
+impl Datasets {
+    /// Text files without an `.idx` sidecar get their prefix index built in memory.
+    fn open(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut datasets = Self {
+            sha1: None,
+            ntlm: None,
+        };
+        for path in paths {
+            let mut dataset = Dataset::open(path)?;
+            dataset.ensure_not_truncated()?;
+            dataset.build_index()?;
+            let mode = dataset.mode();
+            let slot = match mode {
+                HashMode::Sha1 => &mut datasets.sha1,
+                HashMode::Ntlm => &mut datasets.ntlm,
+            };
+            if slot.replace(dataset).is_some() {
+                anyhow::bail!("More than one {} dataset was given", mode.name());
+            }
+        }
+        Ok(datasets)
+    }
+
+    fn get(&self, mode: HashMode) -> Option<&Dataset> {
+        match mode {
+            HashMode::Sha1 => self.sha1.as_ref(),
+            HashMode::Ntlm => self.ntlm.as_ref(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Dataset> {
+        self.sha1.iter().chain(self.ntlm.iter())
+    }
+}
+
+/// Limits on `POST /check` requests.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckLimits {
+    pub max_hashes: usize,
+    pub max_body_bytes: usize,
+}
+
+struct State {
+    datasets: Datasets,
+    limits: CheckLimits,
+    /// The `GET /health` body, which doesn't change while serving.
+    health: String,
+}
+
+#[derive(Deserialize)]
+struct CheckRequest {
+    hashes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CheckResponse {
+    results: Vec<CheckResult>,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    hash: String,
+    /// 0 if the hash was never seen.
+    count: u32,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    datasets: Vec<DatasetInfo>,
+}
+
+#[derive(Serialize)]
+struct DatasetInfo {
+    mode: HashMode,
+    format: Format,
+    manifest: Option<Manifest>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Serves the k-anonymity range API (`GET /range/{prefix}`, with `?mode=ntlm` for NTLM)
+/// from local datasets, answering the same way api.pwnedpasswords.com does.
+/// Alongside it, `POST /check` looks up full hashes and `GET /health` describes the datasets.
+pub fn run_serve(
+    datasets: &[PathBuf],
+    listen: SocketAddr,
+    limits: CheckLimits,
+) -> anyhow::Result<()> {
+    let datasets = Datasets::open(datasets)?;
+    let health = health(&datasets);
+    let state = Arc::new(State {
+        datasets,
+        limits,
+        health,
+    });
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let make_service = make_service_fn(move |_| {
+            let state = Arc::clone(&state);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = Arc::clone(&state);
+                    async move { Ok::<_, Infallible>(handle(state, req).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&listen)?.serve(make_service);
+        eprintln!(
+            "Serving GET /range/{{prefix}}, POST /check and GET /health at http://{}",
+            server.local_addr()
+        );
+        server
+            .with_graceful_shutdown(async {
+                tokio::signal::ctrl_c().await.ok();
+            })
+            .await?;
+        anyhow::Ok(())
+    })
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path();
+    let is_get = req.method() == Method::GET || req.method() == Method::HEAD;
+    match path {
+        "/health" if is_get => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(state.health.clone()))
+            .unwrap(),
+        "/check" if req.method() == Method::POST => check(state, req).await,
+        _ if path.starts_with("/range/") && is_get => range(state, req).await,
+        "/health" | "/check" => text(StatusCode::METHOD_NOT_ALLOWED, ""),
+        _ if path.starts_with("/range/") => text(StatusCode::METHOD_NOT_ALLOWED, ""),
+        _ => text(StatusCode::NOT_FOUND, ""),
+    }
+}
+
+fn health(datasets: &Datasets) -> String {
+    let health = Health {
+        status: "ok",
+        datasets: datasets
+            .iter()
+            .map(|dataset| DatasetInfo {
+                mode: dataset.mode(),
+                format: dataset.format(),
+                // The rows of every prefix are only for the next download to compare with
+                manifest: dataset.manifest().map(|manifest| Manifest {
+                    prefix_rows: Vec::new(),
+                    ..manifest.clone()
+                }),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&health).unwrap()
+}
+
+async fn check(state: Arc<State>, mut req: Request<Body>) -> Response<Body> {
+    let limits = state.limits;
+    let body = match read_body(req.body_mut(), limits.max_body_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            return error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("The body is over {} bytes", limits.max_body_bytes),
+            )
+        }
+        Err(e) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to read the body: {e}"),
+            )
+        }
+    };
+    let request = match serde_json::from_slice::<CheckRequest>(&body) {
+        Ok(request) => request,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid request: {e}")),
+    };
+    if request.hashes.len() > limits.max_hashes {
+        return error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "At most {} hashes can be checked at once",
+                limits.max_hashes
+            ),
+        );
+    }
+    let mut hashes = Vec::with_capacity(request.hashes.len());
+    for hash in &request.hashes {
+        match parse_hash(hash) {
+            Ok((mode, _)) if state.datasets.get(mode).is_none() => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    format!("No {} dataset is being served", mode.name()),
+                )
+            }
+            Ok(parsed) => hashes.push(parsed),
+            Err(e) => return error(StatusCode::BAD_REQUEST, format!("{e}")),
+        }
+    }
+
+    // Reading the dataset blocks, so keep it off the async workers
+    let results = tokio::task::spawn_blocking(move || {
+        hashes
+            .into_iter()
+            .map(|(mode, hash)| {
+                let count = state.datasets.get(mode).unwrap().count(&hash)?;
+                Ok(CheckResult {
+                    hash,
+                    count: count.unwrap_or(0),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await;
+    match results {
+        Ok(Ok(results)) => json(StatusCode::OK, &CheckResponse { results }),
+        Ok(Err(e)) => {
+            warn!("Failed to check hashes: {e:?}");
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the dataset".to_string(),
+            )
+        }
+        Err(e) => {
+            warn!("Failed to check hashes: {e}");
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the dataset".to_string(),
+            )
+        }
+    }
+}
+
+/// Returns `None` if the body is over `limit` bytes.
+async fn read_body(body: &mut Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if out.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        out.extend_from_slice(&chunk);
+    }
+    Ok(Some(out))
+}
+
+async fn range(state: Arc<State>, req: Request<Body>) -> Response<Body> {
+    let prefix = req.uri().path().trim_start_matches("/range/");
+    // from_str_radix alone would take a leading "+"
+    if prefix.len() != 5 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return text(StatusCode::BAD_REQUEST, INVALID_PREFIX);
+    }
+    let prefix = u32::from_str_radix(prefix, 16).unwrap();
+    // Like upstream, anything other than mode=ntlm is SHA1
+    let ntlm = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|pair| pair.eq_ignore_ascii_case("mode=ntlm"));
+    let mode = if ntlm { HashMode::Ntlm } else { HashMode::Sha1 };
+    let add_padding = req
+        .headers()
+        .get("Add-Padding")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
+    if state.datasets.get(mode).is_none() {
+        return text(
+            StatusCode::NOT_FOUND,
+            &format!("No {} dataset is being served", mode.name()),
+        );
+    }
+
+    // Reading the dataset blocks, so keep it off the async workers
+    let rows =
+        tokio::task::spawn_blocking(move || state.datasets.get(mode).unwrap().range(prefix)).await;
+    match rows {
+        Ok(Ok(rows)) => {
+            let mut rows = rows
+                .into_iter()
+                .map(|row| (row.hash[5..].to_string(), row.count))
+                .collect::<Vec<_>>();
+            if add_padding {
+                pad(&mut rows, mode.hex_len() - 5);
+            }
+            let body = rows
+                .iter()
+                .map(|(suffix, count)| format!("{suffix}:{count}"))
+                .collect::<Vec<_>>()
+                .join("\r\n");
+            Response::builder()
+                .header("Content-Type", "text/plain")
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Cache-Control", "public, max-age=2678400")
+                .header("Vary", "Add-Padding")
+                .body(Body::from(body))
+                .unwrap()
+        }
+        Ok(Err(e)) => {
+            warn!("Failed to read prefix {prefix:05X}: {e:?}");
+            text(StatusCode::INTERNAL_SERVER_ERROR, "")
+        }
+        Err(e) => {
+            warn!("Failed to read prefix {prefix:05X}: {e}");
+            text(StatusCode::INTERNAL_SERVER_ERROR, "")
+        }
+    }
+}
+
+/// Adds random `${SUFFIX}:0` rows until there are between 800 and 1000 rows, like
+/// upstream does. The fake suffixes are mixed in with the real ones in sorted order,
+/// so only the count tells them apart.
+fn pad(rows: &mut Vec<(String, u32)>, suffix_len: usize) {
+    let mut rng = rand::thread_rng();
+    let target = rng.gen_range(PADDED_ROWS);
+    let mut suffixes = rows
+        .iter()
+        .map(|(suffix, _)| suffix.clone())
+        .collect::<HashSet<_>>();
+    while rows.len() < target {
+        let suffix = (0..suffix_len)
+            .map(|_| char::from(HEX_DIGITS[rng.gen_range(0..16)]))
+            .collect::<String>();
+        // Never shadow a real row
+        if suffixes.insert(suffix.clone()) {
+            rows.push((suffix, 0));
+        }
+    }
+    rows.sort_unstable();
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, error: String) -> Response<Body> {
+    json(status, &ErrorResponse { error })
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -3,21 +3,50 @@ use extsort::*;
 use std::{
     io::{BufRead, Read, Write},
     path::Path,
+    sync::{atomic, Arc},
 };
 use tracing_indicatif::span_ext::IndicatifSpanExt;
-mod row;
+pub mod row;
 use row::MyStruct;
 
-use super::progress_style::{get_span, progress_style_sort};
+use super::{
+    config::Config,
+    exit_status::{interrupt_flag, ExitStatus, Failure},
+    metrics::MetricsExporter,
+    progress_style::{get_span, leak_span, progress_style_sort},
+    start_json_progress,
+    stats::Stats,
+    summary::{HashingWriter, Summary},
+};
+
+pub fn run_sort(
+    config: &Config,
+    input: &Path,
+    output: &Path,
+    temp_dir: &Path,
+    min_count: u32,
+) -> anyhow::Result<()> {
+    let stats = Arc::new(Stats::new());
+    let result = sort(config, input, output, temp_dir, min_count, &stats);
+    Summary::sort(&stats, output, &result).emit(config.summary_file.as_deref())?;
+    result
+}
 
-pub fn run_sort(input: &Path, output: &Path, temp_dir: &Path) -> anyhow::Result<()> {
+fn sort(
+    config: &Config,
+    input: &Path,
+    output: &Path,
+    temp_dir: &Path,
+    min_count: u32,
+    stats: &Arc<Stats>,
+) -> anyhow::Result<()> {
     // Create the dir if it doesn't exist
     // mkdir -p ${temp_dir}
     std::fs::create_dir_all(temp_dir)?;
 
     let input_byte_size = std::fs::metadata(input)?.len();
     if input_byte_size < 60 {
-        anyhow::bail!("File too small");
+        return Err(Failure::new(ExitStatus::ValidationFailure, "File too small").into());
     }
 
     let hash_size = {
@@ -34,7 +63,13 @@ pub fn run_sort(input: &Path, output: &Path, temp_dir: &Path) -> anyhow::Result<
             .context("No colon in sort file")?;
         let len = hash.len();
         // NTLM or SHA1 (in hex string)
-        assert!(len == 32 || len == 40);
+        if len != 32 && len != 40 {
+            return Err(Failure::new(
+                ExitStatus::ValidationFailure,
+                format!("Hash length {len} is neither SHA1 nor NTLM"),
+            )
+            .into());
+        }
         len
     };
     // colon + average of 4 length number (max 8, min 1) + new line
@@ -42,8 +77,19 @@ pub fn run_sort(input: &Path, output: &Path, temp_dir: &Path) -> anyhow::Result<
     let rows_in_file = input_byte_size / row_size as u64;
 
     // This is a rough estimate.
-    let span = get_span(rows_in_file * 2, progress_style_sort());
-    let enter = span.enter();
+    let span = get_span(rows_in_file * 2, progress_style_sort()).entered();
+    let interrupted = interrupt_flag();
+    stats
+        .sort_length
+        .store(rows_in_file * 2, atomic::Ordering::Release);
+    let metrics = MetricsExporter::start(config, stats)?;
+    let json_progress = start_json_progress(config, "sort", rows_in_file * 2, stats, |s| {
+        s.sort_progress.load(atomic::Ordering::Acquire)
+    });
+    let inc = || {
+        span.pb_inc(1);
+        stats.sort_progress.fetch_add(1, atomic::Ordering::AcqRel);
+    };
 
     // 11.64 million x 45 bytes per struct = 500.5 MB chunks
     let sorter = ExternalSorter::new()
@@ -51,29 +97,84 @@ pub fn run_sort(input: &Path, output: &Path, temp_dir: &Path) -> anyhow::Result<
         .with_sort_dir(temp_dir.to_path_buf())
         .with_segment_size(11_640_000);
     let reader = std::io::BufReader::with_capacity(16 * 1024 * 1024, std::fs::File::open(input)?);
-    let mut writer =
-        std::io::BufWriter::with_capacity(16 * 1024 * 1024, std::fs::File::create(output)?);
-    sorter
-        .sort(reader.lines().map(|s| {
-            span.pb_inc(1);
-            s.unwrap().parse::<MyStruct>().unwrap()
-        }))?
-        .for_each(|data| {
-            span.pb_inc(1);
-            writer.write_all(data.hash.as_bytes()).unwrap();
-            writer.write_all(b":").unwrap();
-            writer.write_all(data.count.to_string().as_bytes()).unwrap();
-            writer.write_all(b"\n").unwrap();
-        });
+    let mut writer = HashingWriter::new(std::io::BufWriter::with_capacity(
+        16 * 1024 * 1024,
+        std::fs::File::create(output)?,
+    ));
+    // The sorter only takes rows, so the first line that can't be read or parsed
+    // stops the input and its error is returned after
+    let failed_line = std::cell::Cell::new(None::<anyhow::Error>);
+    let sorted = sorter.sort(
+        reader
+            .lines()
+            // Stop reading early so Ctrl-C doesn't have to wait for the whole file
+            .take_while(|_| !interrupted.load(atomic::Ordering::Acquire))
+            .enumerate()
+            .map_while(|(number, s)| {
+                inc();
+                let invalid = |e: &dyn std::fmt::Display| {
+                    Failure::new(
+                        ExitStatus::ValidationFailure,
+                        format!("Invalid line {}: {e}", number + 1),
+                    )
+                    .into()
+                };
+                let e = match s {
+                    Ok(s) => match s.parse::<MyStruct>() {
+                        Ok(data) => return Some(data),
+                        Err(e) => invalid(&e),
+                    },
+                    // Not UTF-8, so the row is bad rather than the read
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => invalid(&e),
+                    Err(e) => anyhow::Error::from(e)
+                        .context(format!("Failed to read line {}", number + 1)),
+                };
+                failed_line.set(Some(e));
+                None
+            })
+            .filter(|data| {
+                if data.count >= min_count {
+                    return true;
+                }
+                stats.rows_dropped.fetch_add(1, atomic::Ordering::AcqRel);
+                // It won't be written, so count that step as done too
+                inc();
+                false
+            }),
+    )?;
+    if let Some(e) = failed_line.take() {
+        drop(sorted);
+        std::fs::remove_dir_all(temp_dir)?;
+        return Err(e);
+    }
+    for data in sorted {
+        if interrupted.load(atomic::Ordering::Acquire) {
+            break;
+        }
+        inc();
+        let row = format!("{}:{}\n", data.hash, data.count);
+        writer.write_all(row.as_bytes())?;
+        stats.rows_written.fetch_add(1, atomic::Ordering::AcqRel);
+        stats
+            .bytes_written
+            .fetch_add(row.len() as u64, atomic::Ordering::AcqRel);
+    }
+    let checksum = writer.finish()?;
     // Remove the temp dir after the writing is finished.
     std::fs::remove_dir_all(temp_dir)?;
+    if let Some(json_progress) = json_progress {
+        json_progress.finish();
+    }
+    if let Some(metrics) = metrics {
+        metrics.finish();
+    }
 
-    // Leak the span so that it never gets cleaned up
-    // (We want it to remain after the program finishes so the logs aren't deleted)
-    // Give it a chance to write to stderr (since it can't flush in the Drop impl)
-    std::thread::sleep(std::time::Duration::from_millis(100));
-    core::mem::forget(enter);
-    core::mem::forget(span);
+    leak_span(span);
+
+    if interrupted.load(atomic::Ordering::Acquire) {
+        return Err(Failure::new(ExitStatus::Interrupted, "Interrupted").into());
+    }
+    stats.output_checksum.set(checksum).ok();
 
     Ok(())
 }
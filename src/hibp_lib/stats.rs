@@ -1,7 +1,306 @@
-use std::sync::atomic::AtomicU64;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
 
-pub static DOWNLOADED: AtomicU64 = AtomicU64::new(0);
-pub static WRITTEN_TO_FILE: AtomicU64 = AtomicU64::new(0);
-pub static IN_ROUTE: AtomicU64 = AtomicU64::new(0);
-pub static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
-pub static AVG_TIME_MS: AtomicU64 = AtomicU64::new(0);
+use super::consts::LENGTH;
+
+/// Statistics for a single run.
+///
+/// One instance is created per run and shared (behind an `Arc`) between the
+/// download and writer tasks, the progress bar, and the final summary.
+/// Everything in here is lock-free so that recording never blocks a request.
+pub struct Stats {
+    pub started: SystemTime,
+    pub downloaded: AtomicU64,
+    pub written_to_file: AtomicU64,
+    pub rows_written: AtomicU64,
+    /// Rows left out of the output for being under `--min-count`.
+    pub rows_dropped: AtomicU64,
+    pub bytes_written: AtomicU64,
+    /// Set once the output has been completely written.
+    pub output_checksum: OnceLock<String>,
+    /// Prefixes downloaded but waiting in the writer's reorder buffer.
+    pub buffered: AtomicU64,
+    pub in_route: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub retries: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub rows_received: AtomicU64,
+    /// Rows read plus rows written by the sort subcommand.
+    pub sort_progress: AtomicU64,
+    /// The (estimated) final value of `sort_progress`.
+    pub sort_length: AtomicU64,
+    /// Time taken per prefix, including any retries.
+    pub latency: Histogram,
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    status_codes: Box<[AtomicU64]>,
+    rows_per_prefix: Box<[AtomicU32]>,
+    failed_prefixes: Box<[AtomicBool]>,
+    anomalous_prefixes: Box<[AtomicBool]>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+            downloaded: AtomicU64::new(0),
+            written_to_file: AtomicU64::new(0),
+            rows_written: AtomicU64::new(0),
+            rows_dropped: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            output_checksum: OnceLock::new(),
+            buffered: AtomicU64::new(0),
+            in_route: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            rows_received: AtomicU64::new(0),
+            sort_progress: AtomicU64::new(0),
+            sort_length: AtomicU64::new(0),
+            latency: Histogram::new(),
+            errors: Default::default(),
+            status_codes: (0..MAX_STATUS_CODE).map(|_| AtomicU64::new(0)).collect(),
+            rows_per_prefix: (0..LENGTH).map(|_| AtomicU32::new(0)).collect(),
+            failed_prefixes: (0..LENGTH).map(|_| AtomicBool::new(false)).collect(),
+            anomalous_prefixes: (0..LENGTH).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    pub fn record_error(&self, kind: ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn errors(&self, kind: ErrorKind) -> u64 {
+        self.errors[kind as usize].load(Ordering::Acquire)
+    }
+
+    pub fn total_errors(&self) -> u64 {
+        ErrorKind::ALL.iter().map(|&kind| self.errors(kind)).sum()
+    }
+
+    pub fn record_status(&self, status: u16) {
+        if let Some(counter) = self.status_codes.get(usize::from(status)) {
+            counter.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// All status codes that were seen at least once, with their counts.
+    pub fn status_codes(&self) -> Vec<(u16, u64)> {
+        self.status_codes
+            .iter()
+            .enumerate()
+            .map(|(status, counter)| (status as u16, counter.load(Ordering::Acquire)))
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+
+    pub fn set_prefix_rows(&self, prefix: u32, rows: u32) {
+        self.rows_per_prefix[prefix as usize].store(rows, Ordering::Release);
+        self.rows_received
+            .fetch_add(u64::from(rows), Ordering::AcqRel);
+    }
+
+    pub fn prefix_rows(&self, prefix: u32) -> u32 {
+        self.rows_per_prefix[prefix as usize].load(Ordering::Acquire)
+    }
+
+    /// Marks a prefix that ran out of retries and is missing from the output.
+    pub fn record_failed_prefix(&self, prefix: u32) {
+        self.failed_prefixes[prefix as usize].store(true, Ordering::Release);
+    }
+
+    pub fn is_failed_prefix(&self, prefix: u32) -> bool {
+        self.failed_prefixes[prefix as usize].load(Ordering::Acquire)
+    }
+
+    pub fn failed_prefixes(&self) -> Vec<u32> {
+        (0..LENGTH).filter(|&n| self.is_failed_prefix(n)).collect()
+    }
+
+    /// Marks a prefix that kept far fewer rows than the previous snapshot had,
+    /// even after retrying. It is still written.
+    pub fn record_anomalous_prefix(&self, prefix: u32) {
+        self.anomalous_prefixes[prefix as usize].store(true, Ordering::Release);
+    }
+
+    pub fn anomalous_prefixes(&self) -> Vec<u32> {
+        (0..LENGTH)
+            .filter(|&n| self.anomalous_prefixes[n as usize].load(Ordering::Acquire))
+            .collect()
+    }
+
+    /// The rows downloaded for every prefix, for the manifest.
+    pub fn all_prefix_rows(&self) -> Vec<u32> {
+        (0..LENGTH).map(|n| self.prefix_rows(n)).collect()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Any status code above this is not valid HTTP, so it isn't tracked.
+const MAX_STATUS_CODE: u16 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Connect,
+    Timeout,
+    Status,
+    Body,
+    /// The body was shorter than `Content-Length` or ended partway through a row.
+    Truncated,
+    /// The body had far fewer rows than the previous snapshot.
+    Anomaly,
+    Other,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 7] = [
+        ErrorKind::Connect,
+        ErrorKind::Timeout,
+        ErrorKind::Status,
+        ErrorKind::Body,
+        ErrorKind::Truncated,
+        ErrorKind::Anomaly,
+        ErrorKind::Other,
+    ];
+
+    pub fn of(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ErrorKind::Timeout
+        } else if e.is_connect() {
+            ErrorKind::Connect
+        } else if e.is_status() {
+            ErrorKind::Status
+        } else if e.is_body() || e.is_decode() {
+            ErrorKind::Body
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Connect => "connect",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Status => "status",
+            ErrorKind::Body => "body",
+            ErrorKind::Truncated => "truncated",
+            ErrorKind::Anomaly => "anomaly",
+            ErrorKind::Other => "other",
+        }
+    }
+}
+
+/// Number of sub-buckets per power of two. (2^3 = 8 gives ~12.5% precision)
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A lock-free log-linear histogram of durations (in microseconds).
+///
+/// Values below 8µs get their own bucket, above that every power of two is
+/// split into 8 equally sized buckets.
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(us)].fetch_add(1, Ordering::AcqRel);
+        self.sum_us.fetch_add(us, Ordering::AcqRel);
+        self.count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Ordering::Acquire))
+    }
+
+    /// For each bound, the number of values at or below it.
+    /// A value is only counted once the whole bucket it landed in is below the bound.
+    pub fn cumulative_counts(&self, bounds: &[Duration]) -> Vec<u64> {
+        bounds
+            .iter()
+            .map(|bound| {
+                let bound_us = u64::try_from(bound.as_micros()).unwrap_or(u64::MAX);
+                self.buckets
+                    .iter()
+                    .enumerate()
+                    .take_while(|&(index, _)| bucket_upper_bound(index) <= bound_us)
+                    .map(|(_, b)| b.load(Ordering::Acquire))
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Returns the upper bound of the bucket holding the `q` quantile.
+    /// `q` must be between 0.0 and 1.0.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let counts = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Acquire))
+            .collect::<Vec<_>>();
+        let total = counts.iter().sum::<u64>();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let target = ((total as f64 * q).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (index, count) in counts.into_iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_micros(bucket_upper_bound(index));
+            }
+        }
+        unreachable!("target is never above the total")
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let msb = 63 - value.leading_zeros();
+    let shift = msb - SUB_BUCKET_BITS;
+    let sub = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    (shift as usize + 1) * SUB_BUCKETS + sub
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = (index % SUB_BUCKETS) as u64;
+    let lower = (SUB_BUCKETS as u64 + sub) << shift;
+    lower.saturating_add((1 << shift) - 1)
+}
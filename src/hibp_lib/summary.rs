@@ -0,0 +1,160 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{
+    exit_status::ExitStatus,
+    stats::{ErrorKind, Stats},
+};
+
+/// The structured summary printed (or written to `--summary-file`) at the end of a run.
+#[derive(Serialize)]
+pub struct Summary {
+    command: &'static str,
+    status: ExitStatus,
+    exit_code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    duration_secs: f64,
+    output: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_checksum: Option<String>,
+    rows_written: u64,
+    /// Rows left out for being under `--min-count`.
+    rows_dropped: u64,
+    bytes_written: u64,
+    #[serde(flatten)]
+    download: Option<DownloadSummary>,
+}
+
+#[derive(Serialize)]
+struct DownloadSummary {
+    prefixes_downloaded: u64,
+    prefixes_written: u64,
+    failed_prefixes: Vec<String>,
+    anomalous_prefixes: Vec<String>,
+    rows_received: u64,
+    bytes_received: u64,
+    cache_hit_ratio: f64,
+    retries: u64,
+    errors: u64,
+    /// Responses that were cut short and retried.
+    truncated_bodies: u64,
+}
+
+impl Summary {
+    pub fn download(stats: &Stats, output: &Path, result: &anyhow::Result<()>) -> Self {
+        let load = |counter: &atomic::AtomicU64| counter.load(atomic::Ordering::Acquire);
+        let downloaded = load(&stats.downloaded);
+        let download = DownloadSummary {
+            prefixes_downloaded: downloaded,
+            prefixes_written: load(&stats.written_to_file),
+            failed_prefixes: stats
+                .failed_prefixes()
+                .into_iter()
+                .map(|n| format!("{n:05X}"))
+                .collect(),
+            anomalous_prefixes: stats
+                .anomalous_prefixes()
+                .into_iter()
+                .map(|n| format!("{n:05X}"))
+                .collect(),
+            rows_received: load(&stats.rows_received),
+            bytes_received: load(&stats.bytes_received),
+            cache_hit_ratio: if downloaded == 0 {
+                0.0
+            } else {
+                load(&stats.cache_hits) as f64 / downloaded as f64
+            },
+            retries: load(&stats.retries),
+            errors: stats.total_errors(),
+            truncated_bodies: stats.errors(ErrorKind::Truncated),
+        };
+        Self {
+            download: Some(download),
+            ..Self::new("download", stats, output, result)
+        }
+    }
+
+    pub fn sort(stats: &Stats, output: &Path, result: &anyhow::Result<()>) -> Self {
+        Self::new("sort", stats, output, result)
+    }
+
+    fn new(
+        command: &'static str,
+        stats: &Stats,
+        output: &Path,
+        result: &anyhow::Result<()>,
+    ) -> Self {
+        let status = ExitStatus::of(result);
+        Self {
+            command,
+            status,
+            exit_code: status.code(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            duration_secs: stats
+                .started
+                .elapsed()
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            output: output.to_path_buf(),
+            output_checksum: stats.output_checksum.get().cloned(),
+            rows_written: stats.rows_written.load(atomic::Ordering::Acquire),
+            rows_dropped: stats.rows_dropped.load(atomic::Ordering::Acquire),
+            bytes_written: stats.bytes_written.load(atomic::Ordering::Acquire),
+            download: None,
+        }
+    }
+
+    /// Writes the summary to `path`, or prints it to stdout if there is no path.
+    pub fn emit(&self, path: Option<&Path>) -> std::io::Result<()> {
+        let json = serde_json::to_string(self)?;
+        match path {
+            Some(path) => std::fs::write(path, json + "\n"),
+            None => writeln!(std::io::stdout(), "{json}"),
+        }
+    }
+}
+
+/// Formats the checksum of everything fed to the hasher for the summary.
+pub fn format_checksum(hasher: Sha256) -> String {
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+/// Passes writes through while hashing them for the summary checksum.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Flushes the inner writer and returns the checksum.
+    pub fn finish(mut self) -> std::io::Result<String> {
+        self.inner.flush()?;
+        Ok(format_checksum(self.hasher))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -4,18 +4,19 @@ use std::{
     time::Duration,
 };
 
+use bytes::Bytes;
 use tokio::{
     sync::{mpsc::Receiver, mpsc::Sender, Semaphore},
     task::JoinSet,
 };
-use tracing::Span;
+use tracing::{warn, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 use super::{
     buffered_string_writer::BufferedStringWriter,
     consts::{BEGIN, END},
-    download::download_prefix,
-    stats::DOWNLOADED,
+    download::{download_prefix, PreviousSnapshot},
+    stats::Stats,
     ChannelData,
 };
 
@@ -27,16 +28,14 @@ pub async fn writer_task(
         file.add_file(rows).await?;
     }
 
-    file.flush(false).await?;
-    file.inner_flush().await?;
-    Ok::<(), io::Error>(())
+    file.finish().await
 }
 
-pub async fn progress_task() {
+pub async fn progress_task(stats: Arc<Stats>) {
     let span = Span::current();
     loop {
         tokio::time::sleep(Duration::from_millis(100)).await;
-        span.pb_set_position(DOWNLOADED.load(atomic::Ordering::Acquire));
+        span.pb_set_position(stats.downloaded.load(atomic::Ordering::Acquire));
     }
 }
 
@@ -45,6 +44,9 @@ pub async fn download_task(
     concurrent_requests: usize,
     tx: Sender<ChannelData>,
     ntlm: bool,
+    add_padding: bool,
+    previous: Option<Arc<PreviousSnapshot>>,
+    stats: Arc<Stats>,
 ) -> anyhow::Result<()> {
     let mut handles = JoinSet::new();
     let semaphore = Arc::new(Semaphore::new(concurrent_requests));
@@ -52,10 +54,22 @@ pub async fn download_task(
         let client = client.clone();
         let tx = tx.clone();
         let semaphore = Arc::clone(&semaphore);
+        let stats = Arc::clone(&stats);
+        let previous = previous.clone();
 
         handles.spawn(async move {
             let _permit = semaphore.acquire().await?;
-            tx.send(download_prefix(&client, n, ntlm).await?).await?;
+            match download_prefix(&client, n, ntlm, add_padding, previous.as_deref(), &stats).await
+            {
+                Ok(data) => tx.send(data).await?,
+                // Keep going so the rest of the prefixes still get written
+                Err(e) => {
+                    warn!("Giving up on 0x{n:05X}: {e}");
+                    stats.record_failed_prefix(n);
+                    // The writer skips it, but needs it to keep the output in order
+                    tx.send((n, Bytes::new())).await?;
+                }
+            }
             Ok::<(), anyhow::Error>(())
         });
     }
@@ -0,0 +1,55 @@
+use std::{collections::BinaryHeap, io::Write, path::Path};
+
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    consts::LENGTH,
+    dataset::Dataset,
+    open_output,
+    progress_style::{get_span, leak_span, progress_style_sort, set_prefix_position},
+    sort::row::MyStruct,
+};
+
+/// Writes the `n` most frequently seen hashes, in descending frequency order.
+///
+/// Unlike sort, this reads the dataset (in any format) once and only ever holds
+/// `n` rows, so it needs no temporary files.
+pub fn run_top(dataset: &Path, output: Option<&Path>, n: usize) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    dataset.ensure_not_truncated()?;
+    let span = get_span(u64::from(LENGTH), progress_style_sort()).entered();
+
+    // MyStruct orders by descending count, so the top of the heap is the least
+    // frequent row kept so far, which is the one to drop when a more frequent row comes.
+    let mut heap = BinaryHeap::with_capacity(n.saturating_add(1).min(1 << 20));
+    for row in dataset.rows()? {
+        let row = row?;
+        set_prefix_position(&span, &row.hash);
+        if heap.len() == n
+            && heap
+                .peek()
+                .is_none_or(|least: &MyStruct| row.count <= least.count)
+        {
+            continue;
+        }
+        heap.push(MyStruct {
+            count: row.count,
+            hash: row.hash,
+        });
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+    span.pb_set_position(u64::from(LENGTH));
+
+    let mut writer = open_output(output)?;
+    // Ascending by MyStruct's order is descending by count
+    for data in heap.into_sorted_vec() {
+        writeln!(writer, "{}:{}", data.hash, data.count)?;
+    }
+    writer.flush()?;
+
+    leak_span(span);
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use rand::RngCore;
+use serde::Serialize;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{
+    consts::LENGTH,
+    dataset::{encode_hex, packed::PackedWriter, Dataset},
+    progress_style::{get_span, leak_span, progress_style_sort, set_prefix_position},
+};
+
+/// What was measured while truncating.
+#[derive(Serialize)]
+struct TruncateReport {
+    hash_bytes: usize,
+    input_rows: u64,
+    /// Input rows left out for being under `--min-count`.
+    dropped_rows: u64,
+    output_rows: u64,
+    /// Input rows that were merged into a row with the same truncated hash.
+    merged_rows: u64,
+    /// Output rows that hold more than one input hash.
+    colliding_rows: u64,
+    /// The most input hashes merged into one row.
+    largest_collision: u64,
+    output_bytes: u64,
+    /// The chance a random hash not in the dataset matches a row: the rows divided by
+    /// the number of possible truncated hashes.
+    false_positive_rate: f64,
+    /// How many random hashes were looked up in the output, and how many matched.
+    sampled_hashes: u64,
+    sampled_false_positives: u64,
+    sampled_false_positive_rate: f64,
+}
+
+/// Writes a packed dataset that only stores the first `hash_bytes` bytes of each hash.
+/// Hashes that start with the same bytes are merged into one row, with the sum of
+/// their counts. Prints the collisions it found, and the false positive rate both as
+/// calculated and as measured by looking up `samples` random hashes in the output.
+/// Rows seen fewer than `min_count` times are left out before merging.
+pub fn run_truncate(
+    dataset: &Path,
+    output_file: &Path,
+    hash_bytes: usize,
+    samples: u64,
+    min_count: u32,
+) -> anyhow::Result<()> {
+    let dataset = Dataset::open(dataset)?;
+    let mode = dataset.mode();
+    let full_bytes = mode.hex_len() / 2;
+    if !(3..=full_bytes).contains(&hash_bytes) {
+        anyhow::bail!(
+            "{} hashes can be truncated to between 3 and {full_bytes} bytes, not {hash_bytes}",
+            mode.name()
+        );
+    }
+    dataset.ensure_not_truncated()?;
+    let span = get_span(u64::from(LENGTH), progress_style_sort()).entered();
+
+    let mut writer = PackedWriter::create(output_file, mode, hash_bytes - 2)?;
+    let (mut input_rows, mut dropped_rows) = (0, 0);
+    let (mut output_rows, mut colliding_rows, mut largest_collision) = (0, 0, 0);
+    // The truncated hash being merged, the full hash it started with, its count and size
+    let mut pending: Option<(String, String, u32, u64)> = None;
+    let mut flush = |pending: Option<(String, String, u32, u64)>| -> anyhow::Result<()> {
+        if let Some((_, hash, count, merged)) = pending {
+            writer.write_row(&hash, count)?;
+            output_rows += 1;
+            if merged > 1 {
+                colliding_rows += 1;
+                largest_collision = largest_collision.max(merged);
+            }
+        }
+        Ok(())
+    };
+    for row in dataset.rows()? {
+        let row = row?;
+        input_rows += 1;
+        if row.count < min_count {
+            dropped_rows += 1;
+            continue;
+        }
+        let truncated = &row.hash[..hash_bytes * 2];
+        match &mut pending {
+            Some((key, _, count, merged)) if key == truncated => {
+                *count = count.saturating_add(row.count);
+                *merged += 1;
+            }
+            _ => {
+                let next = (truncated.to_string(), row.hash.clone(), row.count, 1);
+                flush(pending.replace(next))?;
+            }
+        }
+        set_prefix_position(&span, &row.hash);
+    }
+    flush(pending)?;
+    let output_bytes = writer.finish()?.metadata()?.len();
+    span.pb_set_position(u64::from(LENGTH));
+    if let Some(manifest) = dataset.manifest() {
+        manifest
+            .packed(output_rows, min_count, dropped_rows)
+            .write(output_file)?;
+    }
+
+    // Random hashes are (almost certainly) not in the dataset, so any match is a false positive
+    let output = Dataset::open(output_file)?;
+    let mut rng = rand::thread_rng();
+    let mut hash = vec![0; full_bytes];
+    let mut sampled_false_positives = 0;
+    for _ in 0..samples {
+        rng.fill_bytes(&mut hash);
+        if output.count(&encode_hex(&hash))?.is_some() {
+            sampled_false_positives += 1;
+        }
+    }
+
+    let report = TruncateReport {
+        hash_bytes,
+        input_rows,
+        dropped_rows,
+        output_rows,
+        merged_rows: input_rows - dropped_rows - output_rows,
+        colliding_rows,
+        largest_collision,
+        output_bytes,
+        false_positive_rate: output_rows as f64 / 2f64.powi(hash_bytes as i32 * 8),
+        sampled_hashes: samples,
+        sampled_false_positives,
+        sampled_false_positive_rate: if samples == 0 {
+            0.0
+        } else {
+            sampled_false_positives as f64 / samples as f64
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    leak_span(span);
+
+    Ok(())
+}
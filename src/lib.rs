@@ -0,0 +1,37 @@
+//! Read the HaveIBeenPwned password hash datasets written by the `hibp_downloader` CLI.
+//!
+//! [`Dataset`] opens any of them: the single text file (using its `.idx` prefix index
+//! when there is one), a directory of prefix files, or the packed binary format.
+//!
+//! ```no_run
+//! use hibp_downloader::Dataset;
+//!
+//! let dataset = Dataset::open("./hibp_password_hashes.bin".as_ref())?;
+//! // SHA1("password")
+//! if let Some(count) = dataset.count("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8")? {
+//!     println!("Seen {count} times");
+//! }
+//! for row in dataset.range(0x5BAA6)? {
+//!     println!("{}:{}", row.hash, row.count);
+//! }
+//! # anyhow::Ok(())
+//! ```
+//!
+//! [`BloomFilter`] reads the filters written by `hibp_downloader filter build`, for
+//! checking hashes where the whole dataset doesn't fit.
+mod hibp_lib;
+
+pub use hibp_lib::dataset::{
+    bloom::BloomFilter, manifest::Manifest, parse_hash, Dataset, Format, HashMode, Row, Rows,
+};
+
+/// The command line app. This is not part of the library API.
+#[doc(hidden)]
+pub mod cli {
+    pub use super::hibp_lib::{
+        config, exit_status, init_logging_and_progress, run_audit, run_bulk_check,
+        run_check_online, run_check_password, run_download, run_filter_build, run_filter_check,
+        run_index, run_lookup, run_pack, run_serve, run_sort, run_stats, run_top, run_truncate,
+        CheckLimits,
+    };
+}
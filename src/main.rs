@@ -1,18 +1,112 @@
-mod hibp_lib;
-use hibp_lib::{
-    config::{get_config, Commands},
-    init_logging_and_progress, run_download, run_sort,
+use std::{process::ExitCode, time::Duration};
+
+use hibp_downloader::cli::{
+    config::{get_config, Commands, FilterCommands},
+    exit_status::ExitStatus,
+    init_logging_and_progress, run_audit, run_bulk_check, run_check_online, run_check_password,
+    run_download, run_filter_build, run_filter_check, run_index, run_lookup, run_pack, run_serve,
+    run_sort, run_stats, run_top, run_truncate, CheckLimits,
 };
 
-fn main() -> anyhow::Result<()> {
-    init_logging_and_progress();
+fn main() -> ExitCode {
     let config = get_config();
-    match &config.subcommands {
+    init_logging_and_progress(config.progress);
+    let result = match &config.subcommands {
         None => run_download(config),
         Some(Commands::Sort {
             input_file,
             output_file,
             temp_dir,
-        }) => run_sort(input_file, output_file, temp_dir),
+            min_count,
+        }) => run_sort(config, input_file, output_file, temp_dir, *min_count),
+        Some(Commands::Top {
+            dataset,
+            output_file,
+            n,
+        }) => run_top(dataset, output_file.as_deref(), *n),
+        Some(Commands::Stats {
+            dataset,
+            output_file,
+            report_format,
+        }) => run_stats(dataset, output_file.as_deref(), *report_format),
+        Some(Commands::Lookup { hash, dataset }) => run_lookup(hash.as_deref(), dataset),
+        Some(Commands::CheckPassword { dataset, batch }) => run_check_password(dataset, *batch),
+        Some(Commands::Index { dataset }) => run_index(dataset),
+        Some(Commands::Pack {
+            dataset,
+            output_file,
+            min_count,
+        }) => run_pack(dataset, output_file, *min_count),
+        Some(Commands::Truncate {
+            dataset,
+            output_file,
+            hash_bytes,
+            samples,
+            min_count,
+        }) => run_truncate(dataset, output_file, *hash_bytes, *samples, *min_count),
+        Some(Commands::Filter { command }) => match command {
+            FilterCommands::Build {
+                dataset,
+                output_file,
+                fp_rate,
+                min_count,
+            } => run_filter_build(dataset, output_file, *fp_rate, *min_count),
+            FilterCommands::Check { hash, filter } => run_filter_check(hash.as_deref(), filter),
+        },
+        Some(Commands::Audit {
+            input_file,
+            input_format,
+            dataset,
+            output_file,
+            report_format,
+            redact,
+        }) => run_audit(
+            input_file,
+            *input_format,
+            dataset,
+            output_file.as_deref(),
+            *report_format,
+            *redact,
+        ),
+        Some(Commands::CheckOnline {
+            input_file,
+            output_file,
+            cache_dir,
+            cache_ttl,
+            concurrent_requests,
+            decoys,
+            add_padding,
+        }) => run_check_online(
+            input_file,
+            output_file.as_deref(),
+            cache_dir,
+            Duration::from_secs(*cache_ttl),
+            *concurrent_requests,
+            *decoys,
+            *add_padding,
+        ),
+        Some(Commands::Serve {
+            dataset,
+            listen,
+            max_hashes,
+            max_body_bytes,
+        }) => run_serve(
+            dataset,
+            *listen,
+            CheckLimits {
+                max_hashes: *max_hashes,
+                max_body_bytes: *max_body_bytes,
+            },
+        ),
+        Some(Commands::BulkCheck {
+            input_file,
+            dataset,
+            output_file,
+            temp_dir,
+        }) => run_bulk_check(input_file, dataset, output_file.as_deref(), temp_dir),
+    };
+    if let Err(e) = &result {
+        eprintln!("Error: {e:?}");
     }
+    ExitStatus::of(&result).into()
 }